@@ -0,0 +1,43 @@
+-- rows are written behind the actors from a single queue, so a wallet can land
+-- before its user; tables are linked by user_id without foreign keys for that reason
+
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    email TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS wallets (
+    user_id TEXT PRIMARY KEY,
+    balance NUMERIC NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS open_positions (
+    position_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    entry_price NUMERIC NOT NULL,
+    qty NUMERIC NOT NULL,
+    margin NUMERIC NOT NULL,
+    stop_loss NUMERIC,
+    take_profit NUMERIC,
+    leverage NUMERIC,
+    opened_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS open_positions_user_id_idx ON open_positions (user_id);
+
+CREATE TABLE IF NOT EXISTS closed_positions (
+    position_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    entry_price NUMERIC NOT NULL,
+    exit_price NUMERIC NOT NULL,
+    qty NUMERIC NOT NULL,
+    margin NUMERIC NOT NULL,
+    realized_pnl NUMERIC NOT NULL,
+    closed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS closed_positions_user_id_idx ON closed_positions (user_id);
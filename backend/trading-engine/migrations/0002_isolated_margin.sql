@@ -0,0 +1,7 @@
+-- margin is now the collateral posted for the position (notional / leverage plus any extra),
+-- leverage defaults to 1x and the liquidation price is computed once at open
+
+UPDATE open_positions SET leverage = 1 WHERE leverage IS NULL;
+ALTER TABLE open_positions ALTER COLUMN leverage SET NOT NULL;
+
+ALTER TABLE open_positions ADD COLUMN IF NOT EXISTS liquidation_price NUMERIC NOT NULL DEFAULT 0;
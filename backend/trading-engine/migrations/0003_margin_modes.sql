@@ -0,0 +1,7 @@
+-- users without a row trade in isolated mode
+
+CREATE TABLE IF NOT EXISTS margin_modes (
+    user_id TEXT PRIMARY KEY,
+    mode TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
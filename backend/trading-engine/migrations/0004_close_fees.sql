@@ -0,0 +1,3 @@
+-- fees charged on close, realized_pnl stays gross
+
+ALTER TABLE closed_positions ADD COLUMN IF NOT EXISTS fees NUMERIC NOT NULL DEFAULT 0;
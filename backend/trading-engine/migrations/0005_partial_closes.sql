@@ -0,0 +1,17 @@
+-- one row per partial close, the position itself stays in open_positions with the rest
+
+CREATE TABLE IF NOT EXISTS partial_closes (
+    id BIGSERIAL PRIMARY KEY,
+    position_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    entry_price NUMERIC NOT NULL,
+    exit_price NUMERIC NOT NULL,
+    qty NUMERIC NOT NULL,
+    margin NUMERIC NOT NULL,
+    realized_pnl NUMERIC NOT NULL,
+    fees NUMERIC NOT NULL,
+    closed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS partial_closes_position_id_idx ON partial_closes (position_id);
@@ -0,0 +1,7 @@
+-- users without a row hold hedge-style positions, one per order
+
+CREATE TABLE IF NOT EXISTS position_modes (
+    user_id TEXT PRIMARY KEY,
+    mode TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
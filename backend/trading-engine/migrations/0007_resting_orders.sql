@@ -0,0 +1,12 @@
+-- limit orders waiting to fill, their reserved margin is already taken out of the wallet
+
+CREATE TABLE IF NOT EXISTS resting_orders (
+    order_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    request TEXT NOT NULL,
+    reserved NUMERIC NOT NULL,
+    placed_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS resting_orders_user_id_idx ON resting_orders (user_id);
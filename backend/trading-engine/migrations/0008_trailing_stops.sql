@@ -0,0 +1,3 @@
+-- trailing stop with its best mark and current trigger, kept as json since it's only read back whole
+
+ALTER TABLE open_positions ADD COLUMN IF NOT EXISTS trailing_stop TEXT;
@@ -0,0 +1,5 @@
+-- stop-loss and take-profit are mark prices by default now, positions opened before this
+-- had pnl targets
+
+ALTER TABLE open_positions ADD COLUMN IF NOT EXISTS target_mode TEXT NOT NULL DEFAULT 'pnl';
+ALTER TABLE open_positions ALTER COLUMN target_mode SET DEFAULT 'price';
@@ -0,0 +1,10 @@
+-- margin held for resting orders moves out of the spendable balance into its own column,
+-- it was already taken out of the balance so the existing holds are just summed up
+
+ALTER TABLE wallets ADD COLUMN IF NOT EXISTS reserved NUMERIC NOT NULL DEFAULT 0;
+
+UPDATE wallets SET reserved = (
+    SELECT COALESCE(SUM(resting_orders.reserved), 0)
+    FROM resting_orders
+    WHERE resting_orders.user_id = wallets.user_id
+);
@@ -0,0 +1,22 @@
+-- wallets hold a balance per currency, split into what is available and what is locked
+-- for resting orders; the old single balance was the USDC collateral
+
+CREATE TABLE IF NOT EXISTS wallet_balances (
+    user_id TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    available NUMERIC NOT NULL DEFAULT 0,
+    locked NUMERIC NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, currency)
+);
+
+INSERT INTO wallet_balances (user_id, currency, available, locked)
+SELECT user_id, 'USDC', balance, reserved FROM wallets
+ON CONFLICT (user_id, currency) DO NOTHING;
+
+INSERT INTO wallet_balances (user_id, currency)
+SELECT wallets.user_id, currencies.currency
+FROM wallets CROSS JOIN (VALUES ('BTC'), ('ETH'), ('SOL')) AS currencies (currency)
+ON CONFLICT (user_id, currency) DO NOTHING;
+
+ALTER TABLE wallets DROP COLUMN IF EXISTS balance;
+ALTER TABLE wallets DROP COLUMN IF EXISTS reserved;
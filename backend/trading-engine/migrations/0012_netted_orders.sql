@@ -0,0 +1,9 @@
+-- one-way orders that netted into an existing position, so a redelivered one is recognised
+-- after a restart; position is the json of the net position the order left behind
+
+CREATE TABLE IF NOT EXISTS netted_orders (
+    order_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    position TEXT NOT NULL,
+    netted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
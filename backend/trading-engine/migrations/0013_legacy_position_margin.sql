@@ -0,0 +1,8 @@
+-- positions opened before 0002 paid the full notional plus `margin` as extra collateral, but
+-- margin now means all of the collateral backing a position; fold the notional they paid into
+-- it so closing them returns what was debited. legacy rows are the ones opened before 0002
+-- was installed, a database still on 0001 installs it in this same run
+
+UPDATE open_positions
+SET margin = entry_price * abs(qty) + margin
+WHERE opened_at < (SELECT installed_on FROM _sqlx_migrations WHERE version = 2);
@@ -0,0 +1,9 @@
+-- replies to commands that move money by request id, so a redelivered one is answered
+-- again instead of being applied twice; response is the json the engine replied with
+
+CREATE TABLE IF NOT EXISTS handled_requests (
+    request_id TEXT PRIMARY KEY,
+    topic TEXT NOT NULL,
+    response TEXT NOT NULL,
+    handled_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
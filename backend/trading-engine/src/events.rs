@@ -0,0 +1,160 @@
+use redis::Commands;
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::types::positions::RiskEvent;
+
+const LIQUIDATIONS_CHANNEL: &str = "liquidations";
+
+/// Payload of the redis `liquidations` channel, matches `LiquidationMessage` in ws-server.
+#[derive(Serialize, Clone, Debug)]
+pub struct LiquidationMessage {
+    pub positions: Vec<RiskEvent>,
+}
+
+pub trait EventPublisher: Send {
+    fn publish(&mut self, message: &LiquidationMessage) -> Result<(), String>;
+}
+
+pub struct RedisPublisher {
+    client: redis::Client,
+    connection: Option<redis::Connection>,
+}
+
+impl RedisPublisher {
+    pub fn new(url: &str) -> Result<RedisPublisher, String> {
+        let client = redis::Client::open(url).map_err(|err| err.to_string())?;
+        Ok(RedisPublisher {
+            client,
+            connection: None,
+        })
+    }
+}
+
+impl EventPublisher for RedisPublisher {
+    fn publish(&mut self, message: &LiquidationMessage) -> Result<(), String> {
+        let payload = serde_json::to_string(message).map_err(|err| err.to_string())?;
+
+        // connect lazily so the engine can come up before redis does
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => self.connection.insert(
+                self.client
+                    .get_connection()
+                    .map_err(|err| err.to_string())?,
+            ),
+        };
+
+        if let Err(err) = connection.publish::<_, _, i64>(LIQUIDATIONS_CHANNEL, payload) {
+            // drop the connection so the next batch reconnects
+            self.connection = None;
+            return Err(err.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Publishes one message per risk pass, blocks so it should run on a blocking thread.
+pub fn run_publisher(
+    mut publisher: impl EventPublisher,
+    mut event_rx: UnboundedReceiver<Vec<RiskEvent>>,
+) {
+    while let Some(events) = event_rx.blocking_recv() {
+        if events.is_empty() {
+            continue;
+        }
+
+        let message = LiquidationMessage { positions: events };
+        if let Err(err) = publisher.publish(&message) {
+            eprintln!(
+                "[EVENT PUBLISHER] dropped {} events: {}",
+                message.positions.len(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::Utc;
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::types::positions::CloseReason;
+
+    /// Keeps every published message in memory.
+    #[derive(Clone, Default)]
+    struct InMemoryPublisher {
+        published: Arc<Mutex<Vec<LiquidationMessage>>>,
+    }
+
+    impl EventPublisher for InMemoryPublisher {
+        fn publish(&mut self, message: &LiquidationMessage) -> Result<(), String> {
+            self.published
+                .lock()
+                .map_err(|err| err.to_string())?
+                .push(message.clone());
+            Ok(())
+        }
+    }
+
+    fn event(position_id: &str) -> RiskEvent {
+        RiskEvent {
+            user_id: "user".to_string(),
+            position_id: position_id.to_string(),
+            asset: "BTC".to_string(),
+            reason: CloseReason::Liquidation,
+            qty: dec!(1),
+            entry_price: dec!(100),
+            exit_price: dec!(90),
+            realized_pnl: dec!(-10),
+            fees: dec!(0.09),
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn publishes_one_message_per_risk_pass() {
+        let publisher = InMemoryPublisher::default();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        event_tx.send(vec![event("a"), event("b")]).unwrap();
+        event_tx.send(vec![event("c")]).unwrap();
+        drop(event_tx);
+
+        run_publisher(publisher.clone(), event_rx);
+
+        let published = publisher.published.lock().unwrap();
+        let batches: Vec<Vec<&str>> = published
+            .iter()
+            .map(|message| {
+                message
+                    .positions
+                    .iter()
+                    .map(|event| event.position_id.as_str())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(batches, vec![vec!["a", "b"], vec!["c"]]);
+    }
+
+    #[test]
+    fn skips_risk_passes_without_events() {
+        let publisher = InMemoryPublisher::default();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        event_tx.send(Vec::new()).unwrap();
+        event_tx.send(vec![event("a")]).unwrap();
+        event_tx.send(Vec::new()).unwrap();
+        drop(event_tx);
+
+        run_publisher(publisher.clone(), event_rx);
+
+        assert_eq!(publisher.published.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    oneshot,
+};
+
+use crate::kafka::{ConsumerOffsets, HandledRequest};
+use crate::storage::{StorageMsg, StoredState};
+use crate::types::{
+    orders::{NettedOrder, PendingOrder},
+    positions::{MarginMode, Position, PositionMode},
+    types::KafkaMessages,
+    users::User,
+    wallet::Wallet,
+};
+
+// a new segment file is started once the current one holds this many entries
+const SEGMENT_MAX_ENTRIES: u64 = 100_000;
+const SEGMENT_EXTENSION: &str = "journal";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JournalRecord {
+    Command {
+        topic: String,
+        partition: i32,
+        offset: i64,
+        message: Box<KafkaMessages>,
+    },
+    StateChange(Box<StorageMsg>),
+}
+
+enum JournalMsg {
+    Record(Box<JournalRecord>),
+    // replies with the seq of the last entry once everything queued before it is on disk,
+    // or with the first append or sync that failed
+    Checkpoint(oneshot::Sender<Result<u64, String>>),
+}
+
+/// One line of a journal segment.
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub record: JournalRecord,
+}
+
+/// Cheap to clone sender for `JournalRecord`s, a no-op when journaling is off.
+#[derive(Clone, Debug)]
+pub struct JournalHandle {
+    journal_tx: Option<UnboundedSender<JournalMsg>>,
+}
+
+impl JournalHandle {
+    pub fn disabled() -> JournalHandle {
+        JournalHandle { journal_tx: None }
+    }
+
+    pub fn record(&self, record: JournalRecord) {
+        if let Some(journal_tx) = &self.journal_tx {
+            if journal_tx
+                .send(JournalMsg::Record(Box::new(record)))
+                .is_err()
+            {
+                eprintln!("[JOURNAL] writer channel closed");
+            }
+        }
+    }
+
+    /// Seq of the last journaled entry, 0 when journaling is off.
+    pub async fn checkpoint(&self) -> Result<u64, String> {
+        let journal_tx = match &self.journal_tx {
+            Some(journal_tx) => journal_tx,
+            None => return Ok(0),
+        };
+
+        let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<u64, String>>();
+        journal_tx
+            .send(JournalMsg::Checkpoint(oneshot_tx))
+            .map_err(|_| "journal writer channel closed".to_string())?;
+
+        oneshot_rx
+            .await
+            .map_err(|_| "journal writer dropped checkpoint".to_string())?
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.journal_tx.is_some()
+    }
+}
+
+/// Appends entries to numbered segment files in `dir`, named after their first seq.
+pub struct Journal {
+    dir: PathBuf,
+    next_seq: u64,
+    segment_entries: u64,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Journal {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Journal, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+
+        // only the newest segment needs reading to find where the journal ends
+        let last_segment_start = segment_paths(&dir)?
+            .last()
+            .and_then(|path| segment_first_seq(path))
+            .unwrap_or(1);
+        let last_seq = for_each_entry(&dir, last_segment_start - 1, |_| {})?;
+
+        // always start a fresh segment so we never append after a torn line
+        Ok(Journal {
+            dir,
+            next_seq: last_seq + 1,
+            segment_entries: 0,
+            writer: None,
+        })
+    }
+
+    fn append(&mut self, record: JournalRecord) -> Result<(), String> {
+        if self.writer.is_none() || self.segment_entries >= SEGMENT_MAX_ENTRIES {
+            self.sync()?;
+            // a segment named after next_seq can only hold a torn first entry, so it is safe to reset
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(segment_path(&self.dir, self.next_seq))
+                .map_err(|err| err.to_string())?;
+            self.writer = Some(BufWriter::new(file));
+            self.segment_entries = 0;
+        }
+
+        let entry = JournalEntry {
+            seq: self.next_seq,
+            timestamp: Utc::now(),
+            record,
+        };
+
+        if let Some(writer) = self.writer.as_mut() {
+            serde_json::to_writer(&mut *writer, &entry).map_err(|err| err.to_string())?;
+            writer.write_all(b"\n").map_err(|err| err.to_string())?;
+        }
+
+        self.next_seq += 1;
+        self.segment_entries += 1;
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush().map_err(|err| err.to_string())?;
+            writer
+                .get_ref()
+                .sync_data()
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Spawns the writer on a blocking thread, syncing to disk whenever the queue drains.
+    pub fn spawn_writer(mut self) -> JournalHandle {
+        let (journal_tx, mut journal_rx) = mpsc::unbounded_channel::<JournalMsg>();
+
+        tokio::task::spawn_blocking(move || {
+            // a lost entry leaves a gap replay can't fill, so the error is never cleared
+            let mut failed: Option<String> = None;
+
+            while let Some(msg) = journal_rx.blocking_recv() {
+                let mut msg = Some(msg);
+
+                while let Some(next) = msg.take().or_else(|| journal_rx.try_recv().ok()) {
+                    match next {
+                        JournalMsg::Record(record) => {
+                            if let Err(err) = self.append(*record) {
+                                eprintln!("[JOURNAL] append failed: {}", err);
+                                failed.get_or_insert_with(|| format!("append failed: {}", err));
+                            }
+                        }
+                        JournalMsg::Checkpoint(responder) => {
+                            if let Err(err) = self.sync() {
+                                eprintln!("[JOURNAL] sync failed: {}", err);
+                                failed.get_or_insert_with(|| format!("sync failed: {}", err));
+                            }
+                            let checkpoint = match &failed {
+                                Some(err) => Err(err.clone()),
+                                None => Ok(self.next_seq - 1),
+                            };
+                            if responder.send(checkpoint).is_err() {
+                                eprintln!("[JOURNAL] checkpoint responder closed");
+                            }
+                        }
+                    }
+                }
+
+                if let Err(err) = self.sync() {
+                    eprintln!("[JOURNAL] sync failed: {}", err);
+                    failed.get_or_insert_with(|| format!("sync failed: {}", err));
+                }
+            }
+        });
+
+        JournalHandle {
+            journal_tx: Some(journal_tx),
+        }
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_seq, SEGMENT_EXTENSION))
+}
+
+fn segment_first_seq(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
+
+fn segment_paths(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = fs::read_dir(dir)
+        .map_err(|err| err.to_string())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION))
+        .collect::<Vec<PathBuf>>();
+
+    // zero padded names sort in seq order
+    paths.sort();
+    Ok(paths)
+}
+
+/// Calls `f` with every entry after `after_seq` in seq order and returns the last seq
+/// seen. Segments that end before `after_seq` are not read at all.
+/// A torn last line in a segment, as left by a crash mid-write, is skipped since the
+/// next run starts a fresh segment; anything else unreadable or out of sequence is an error.
+pub fn for_each_entry(
+    dir: &Path,
+    after_seq: u64,
+    mut f: impl FnMut(JournalEntry),
+) -> Result<u64, String> {
+    let paths = segment_paths(dir)?;
+
+    // the last segment starting at or before the first entry we need
+    let start = paths
+        .iter()
+        .rposition(|path| segment_first_seq(path).is_some_and(|seq| seq <= after_seq + 1))
+        .unwrap_or(0);
+
+    let mut last_seq = match paths.get(start).and_then(|path| segment_first_seq(path)) {
+        Some(first_seq) => first_seq - 1,
+        None => return Ok(after_seq),
+    };
+
+    for path in paths[start..].iter() {
+        let file = File::open(path).map_err(|err| err.to_string())?;
+        let mut lines = BufReader::new(file).lines().peekable();
+
+        while let Some(line) = lines.next() {
+            let line = line.map_err(|err| err.to_string())?;
+            let is_tail = lines.peek().is_none();
+
+            let entry: JournalEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(err) if is_tail => {
+                    eprintln!(
+                        "[JOURNAL] ignoring torn entry at end of {:?}: {}",
+                        path, err
+                    );
+                    break;
+                }
+                Err(err) => return Err(format!("corrupt entry in {:?}: {}", path, err)),
+            };
+
+            if entry.seq != last_seq + 1 {
+                return Err(format!(
+                    "journal gap in {:?}: expected seq {}, found {}",
+                    path,
+                    last_seq + 1,
+                    entry.seq
+                ));
+            }
+
+            last_seq = entry.seq;
+            if entry.seq > after_seq {
+                f(entry);
+            }
+        }
+    }
+
+    Ok(last_seq.max(after_seq))
+}
+
+pub struct Replayed {
+    pub state: StoredState,
+    pub last_seq: u64,
+    pub offsets: ConsumerOffsets,
+}
+
+/// Rebuilds users, wallets and open positions by applying every state change journaled
+/// after `after_seq` on top of `base`, tracking the kafka offsets of replayed commands.
+pub fn replay(
+    dir: impl AsRef<Path>,
+    base: StoredState,
+    after_seq: u64,
+    base_offsets: ConsumerOffsets,
+) -> Result<Replayed, String> {
+    let mut users: HashMap<String, User> = base
+        .users
+        .into_iter()
+        .map(|user| (user.id.clone(), user))
+        .collect();
+    let mut wallets: HashMap<String, Wallet> = base
+        .wallets
+        .into_iter()
+        .map(|wallet| (wallet.user_id.clone(), wallet))
+        .collect();
+    let mut positions: HashMap<String, Vec<Position>> = HashMap::new();
+    for (user_id, position) in base.positions {
+        positions.entry(user_id).or_default().push(position);
+    }
+    let mut margin_modes: HashMap<String, MarginMode> = base.margin_modes.into_iter().collect();
+    let mut position_modes: HashMap<String, PositionMode> =
+        base.position_modes.into_iter().collect();
+    let mut resting_orders: Vec<PendingOrder> = base.resting_orders;
+    let mut netted_orders: HashMap<String, NettedOrder> = base
+        .netted_orders
+        .into_iter()
+        .map(|netted| (netted.order_id.clone(), netted))
+        .collect();
+    let mut handled_requests: HashMap<String, HandledRequest> = base
+        .handled_requests
+        .into_iter()
+        .map(|handled| (handled.request_id.clone(), handled))
+        .collect();
+    let mut offsets = base_offsets;
+
+    let last_seq = for_each_entry(dir.as_ref(), after_seq, |entry| {
+        let change = match entry.record {
+            JournalRecord::StateChange(change) => *change,
+            JournalRecord::Command {
+                topic,
+                partition,
+                offset,
+                ..
+            } => {
+                offsets.record(&topic, partition, offset);
+                return;
+            }
+        };
+
+        match change {
+            StorageMsg::UpsertUser(user) => {
+                users.insert(user.id.clone(), user);
+            }
+            StorageMsg::UpsertWallet(wallet) => {
+                wallets.insert(wallet.user_id.clone(), wallet);
+            }
+            StorageMsg::OpenPosition { user_id, position } => {
+                let user_positions = positions.entry(user_id).or_default();
+                match user_positions
+                    .iter_mut()
+                    .find(|p| p.position_id == position.position_id)
+                {
+                    Some(existing) => *existing = position,
+                    None => user_positions.push(position),
+                }
+            }
+            StorageMsg::ClosePosition { user_id, closed } => {
+                if let Some(user_positions) = positions.get_mut(&user_id) {
+                    user_positions.retain(|p| p.position_id != closed.position.position_id);
+                }
+            }
+            StorageMsg::ReducePosition { user_id, closed } => {
+                if let (Some(user_positions), Some(remaining)) =
+                    (positions.get_mut(&user_id), closed.remaining)
+                {
+                    if let Some(existing) = user_positions
+                        .iter_mut()
+                        .find(|p| p.position_id == remaining.position_id)
+                    {
+                        *existing = remaining;
+                    }
+                }
+            }
+            StorageMsg::PlaceOrder(pending) => {
+                if !resting_orders
+                    .iter()
+                    .any(|p| p.order.order_id == pending.order.order_id)
+                {
+                    resting_orders.push(pending);
+                }
+            }
+            StorageMsg::RemoveOrder { order_id } => {
+                resting_orders.retain(|p| p.order.order_id != order_id);
+            }
+            StorageMsg::SetMarginMode { user_id, mode } => {
+                margin_modes.insert(user_id, mode);
+            }
+            StorageMsg::SetPositionMode { user_id, mode } => {
+                position_modes.insert(user_id, mode);
+            }
+            StorageMsg::NetOrder(netted) => {
+                netted_orders.insert(netted.order_id.clone(), netted);
+            }
+            StorageMsg::HandleRequest(handled) => {
+                handled_requests.insert(handled.request_id.clone(), handled);
+            }
+        }
+    })?;
+
+    Ok(Replayed {
+        state: StoredState {
+            users: users.into_values().collect(),
+            wallets: wallets.into_values().collect(),
+            positions: positions
+                .into_iter()
+                .flat_map(|(user_id, positions)| {
+                    positions
+                        .into_iter()
+                        .map(move |position| (user_id.clone(), position))
+                })
+                .collect(),
+            margin_modes: margin_modes.into_iter().collect(),
+            position_modes: position_modes.into_iter().collect(),
+            resting_orders,
+            netted_orders: netted_orders.into_values().collect(),
+            handled_requests: handled_requests.into_values().collect(),
+        },
+        last_seq,
+        offsets,
+    })
+}
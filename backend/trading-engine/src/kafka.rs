@@ -1,22 +1,361 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::message::{BorrowedMessage, Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{StorageHandle, StorageMsg};
 use crate::types::types::{
-    CreateUserMessage, IncomingPrices, KafkaMessages, OpenOrderRequest, SignUpRequest,
+    CancelOrderRequest, CloseOrderRequest, DepositRequest, IncomingPrices, KafkaMessages,
+    ListPositionsRequest, ModifyPositionRequest, OpenOrderRequest, SetMarginModeRequest,
+    SetPositionModeRequest, SetTargetsRequest, SignUpRequest,
 };
 
-pub fn handle_kafka_message(key: &str, message: &str) -> KafkaMessages {
-    println!("{}", message);
-    match key {
-        "price" => {
-            let prices: IncomingPrices = serde_json::from_str(message).unwrap();
-            return KafkaMessages::IncomingPrices(prices);
+pub const INPUT_TOPIC: &str = "priceUpdate";
+pub const ORDER_RESPONSES_TOPIC: &str = "orderResponses";
+pub const USER_RESPONSES_TOPIC: &str = "userResponses";
+pub const DEAD_LETTER_TOPIC: &str = "deadLetters";
+// header replies carry the `correlation_id` of the request they answer in
+pub const CORRELATION_ID_HEADER: &str = "correlation_id";
+
+// newest envelope schema this engine understands
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Every message on the engine's input topic is wrapped in one of these, e.g.
+/// `{"type": "order", "version": 1, "correlation_id": "..", "timestamp": "..", "payload": {..}}`
+#[derive(Deserialize, Debug)]
+pub struct Envelope {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub version: u32,
+    pub correlation_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub payload: serde_json::Value,
+}
+
+/// Last offset the engine has consumed, per topic and partition.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct ConsumerOffsets {
+    offsets: HashMap<String, HashMap<i32, i64>>,
+}
+
+impl ConsumerOffsets {
+    pub fn record(&mut self, topic: &str, partition: i32, offset: i64) {
+        let last = self
+            .offsets
+            .entry(topic.to_string())
+            .or_default()
+            .entry(partition)
+            .or_insert(offset);
+        *last = (*last).max(offset);
+    }
+
+    /// Offset to resume `partition` from, i.e. one past the last consumed message.
+    pub fn next_offset(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.offsets
+            .get(topic)
+            .and_then(|partitions| partitions.get(&partition))
+            .map(|offset| offset + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Whether `other` has consumed past these offsets on any partition.
+    pub fn is_behind(&self, other: &ConsumerOffsets) -> bool {
+        other.offsets.iter().any(|(topic, partitions)| {
+            partitions.iter().any(|(partition, offset)| {
+                self.next_offset(topic, *partition)
+                    .is_none_or(|next| next <= *offset)
+            })
+        })
+    }
+}
+
+/// Reply the engine published for a handled request, kept to answer a redelivery with.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HandledRequest {
+    pub request_id: String,
+    pub topic: String,
+    pub response: serde_json::Value,
+}
+
+/// Requests whose effects are persisted, see `KafkaMessages::request_id`.
+#[derive(Default)]
+pub struct HandledRequests {
+    requests: HashMap<String, HandledRequest>,
+}
+
+impl HandledRequests {
+    pub fn hydrate(&mut self, handled: Vec<HandledRequest>) {
+        for request in handled {
+            self.requests.insert(request.request_id.clone(), request);
+        }
+    }
+
+    pub fn get(&self, request_id: &str) -> Option<&HandledRequest> {
+        self.requests.get(request_id)
+    }
+
+    /// Remembers `reply` to `request_id`, written behind with the state changes it caused.
+    pub fn record<T: Serialize>(
+        &mut self,
+        storage: &StorageHandle,
+        request_id: &str,
+        topic: &str,
+        reply: &T,
+    ) {
+        if request_id.is_empty() {
+            return;
         }
-        "order" => {
-            let order: OpenOrderRequest = serde_json::from_str(message).unwrap();
-            return KafkaMessages::Order(order);
+
+        let response = match serde_json::to_value(reply) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("[HANDLED REQUEST] could not record {}: {}", request_id, err);
+                return;
+            }
+        };
+        let handled = HandledRequest {
+            request_id: request_id.to_string(),
+            topic: topic.to_string(),
+            response,
+        };
+
+        storage.send(StorageMsg::HandleRequest(handled.clone()));
+        self.requests.insert(handled.request_id.clone(), handled);
+    }
+
+    pub fn snapshot(&self) -> Vec<HandledRequest> {
+        self.requests.values().cloned().collect()
+    }
+}
+
+/// Offsets the consumer group last committed on the input topic, as consumed offsets.
+pub fn committed_offsets(consumer: &StreamConsumer) -> Result<ConsumerOffsets, String> {
+    let metadata = consumer
+        .fetch_metadata(Some(INPUT_TOPIC), Duration::from_secs(10))
+        .map_err(|err| err.to_string())?;
+    let mut partitions = TopicPartitionList::new();
+    for topic in metadata.topics() {
+        for partition in topic.partitions() {
+            partitions.add_partition(INPUT_TOPIC, partition.id());
         }
-        "createUser" => {
-            let email = message.to_string();
-            return KafkaMessages::CreateUser(SignUpRequest { email: email });
+    }
+
+    let committed = consumer
+        .committed_offsets(partitions, Duration::from_secs(10))
+        .map_err(|err| err.to_string())?;
+
+    // a committed offset is the next one to consume
+    let mut offsets = ConsumerOffsets::default();
+    for element in committed.elements() {
+        if let Offset::Offset(next) = element.offset() {
+            if next > 0 {
+                offsets.record(element.topic(), element.partition(), next - 1);
+            }
         }
-        _ => return KafkaMessages::InvalidMessage,
     }
+
+    Ok(offsets)
+}
+
+pub struct IncomingMessage {
+    pub correlation_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub message: KafkaMessages,
+}
+
+#[derive(Debug)]
+pub enum KafkaMessageError {
+    EmptyPayload,
+    MalformedEnvelope(String),
+    UnsupportedVersion { message_type: String, version: u32 },
+    UnknownType(String),
+    InvalidPayload { message_type: String, error: String },
+}
+
+impl fmt::Display for KafkaMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KafkaMessageError::EmptyPayload => write!(f, "message has no payload"),
+            KafkaMessageError::MalformedEnvelope(err) => write!(f, "malformed envelope: {}", err),
+            KafkaMessageError::UnsupportedVersion {
+                message_type,
+                version,
+            } => write!(
+                f,
+                "unsupported schema version {} for {}, newest supported is {}",
+                version, message_type, SCHEMA_VERSION
+            ),
+            KafkaMessageError::UnknownType(message_type) => {
+                write!(f, "unknown message type {}", message_type)
+            }
+            KafkaMessageError::InvalidPayload {
+                message_type,
+                error,
+            } => write!(f, "invalid {} payload: {}", message_type, error),
+        }
+    }
+}
+
+impl KafkaMessageError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KafkaMessageError::EmptyPayload => "empty_payload",
+            KafkaMessageError::MalformedEnvelope(_) => "malformed_envelope",
+            KafkaMessageError::UnsupportedVersion { .. } => "unsupported_version",
+            KafkaMessageError::UnknownType(_) => "unknown_type",
+            KafkaMessageError::InvalidPayload { .. } => "invalid_payload",
+        }
+    }
+}
+
+/// Raw payload and decode error of a message the engine could not handle,
+/// published to `deadLetters` so the consumer can keep going.
+#[derive(Serialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<String>,
+    pub correlation_id: Option<String>,
+    pub raw_payload: String,
+    pub error_kind: &'static str,
+    pub error: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+fn decode_payload<T: DeserializeOwned>(envelope: Envelope) -> Result<T, KafkaMessageError> {
+    serde_json::from_value(envelope.payload).map_err(|err| KafkaMessageError::InvalidPayload {
+        message_type: envelope.message_type,
+        error: err.to_string(),
+    })
+}
+
+pub fn handle_kafka_message(payload: Option<&[u8]>) -> Result<IncomingMessage, KafkaMessageError> {
+    let payload = payload
+        .filter(|payload| !payload.is_empty())
+        .ok_or(KafkaMessageError::EmptyPayload)?;
+
+    let envelope: Envelope = serde_json::from_slice(payload)
+        .map_err(|err| KafkaMessageError::MalformedEnvelope(err.to_string()))?;
+
+    if envelope.version == 0 || envelope.version > SCHEMA_VERSION {
+        return Err(KafkaMessageError::UnsupportedVersion {
+            message_type: envelope.message_type,
+            version: envelope.version,
+        });
+    }
+
+    let correlation_id = envelope.correlation_id.clone();
+    let timestamp = envelope.timestamp;
+
+    let message = match envelope.message_type.as_str() {
+        "price" => KafkaMessages::IncomingPrices(decode_payload::<IncomingPrices>(envelope)?),
+        "order" => KafkaMessages::Order(decode_payload::<OpenOrderRequest>(envelope)?),
+        "closeOrder" => KafkaMessages::CloseOrder(decode_payload::<CloseOrderRequest>(envelope)?),
+        "cancelOrder" => {
+            KafkaMessages::CancelOrder(decode_payload::<CancelOrderRequest>(envelope)?)
+        }
+        "listPositions" => {
+            KafkaMessages::ListPositions(decode_payload::<ListPositionsRequest>(envelope)?)
+        }
+        "createUser" => KafkaMessages::CreateUser(decode_payload::<SignUpRequest>(envelope)?),
+        "setMarginMode" => {
+            KafkaMessages::SetMarginMode(decode_payload::<SetMarginModeRequest>(envelope)?)
+        }
+        "setPositionMode" => {
+            KafkaMessages::SetPositionMode(decode_payload::<SetPositionModeRequest>(envelope)?)
+        }
+        "setTargets" => KafkaMessages::SetTargets(decode_payload::<SetTargetsRequest>(envelope)?),
+        "modifyPosition" => {
+            KafkaMessages::ModifyPosition(decode_payload::<ModifyPositionRequest>(envelope)?)
+        }
+        "deposit" => KafkaMessages::Deposit(decode_payload::<DepositRequest>(envelope)?),
+        _ => return Err(KafkaMessageError::UnknownType(envelope.message_type)),
+    };
+
+    Ok(IncomingMessage {
+        correlation_id,
+        timestamp,
+        message,
+    })
+}
+
+/// Best effort read of the `correlation_id` of a message whose envelope may not decode.
+fn raw_correlation_id(payload: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    value.get("correlation_id")?.as_str().map(str::to_string)
+}
+
+pub async fn publish_reply<T: Serialize>(
+    producer: &FutureProducer,
+    topic: &str,
+    key: &str,
+    correlation_id: Option<&str>,
+    reply: &T,
+) -> Result<(), String> {
+    let payload = serde_json::to_string(reply).map_err(|err| err.to_string())?;
+
+    let mut record = FutureRecord::to(topic).key(key).payload(&payload);
+    if let Some(correlation_id) = correlation_id {
+        record = record.headers(OwnedHeaders::new().insert(Header {
+            key: CORRELATION_ID_HEADER,
+            value: Some(correlation_id),
+        }));
+    }
+
+    producer
+        .send(record, Duration::from_secs(5))
+        .await
+        .map_err(|(err, _)| err.to_string())?;
+
+    Ok(())
+}
+
+pub async fn publish_dead_letter(
+    producer: &FutureProducer,
+    message: &BorrowedMessage<'_>,
+    error: &KafkaMessageError,
+) -> Result<(), String> {
+    let key = message
+        .key()
+        .map(|key| String::from_utf8_lossy(key).to_string());
+
+    let dead_letter = DeadLetter {
+        topic: message.topic().to_string(),
+        partition: message.partition(),
+        offset: message.offset(),
+        key: key.clone(),
+        correlation_id: raw_correlation_id(message.payload().unwrap_or_default()),
+        raw_payload: String::from_utf8_lossy(message.payload().unwrap_or_default()).to_string(),
+        error_kind: error.kind(),
+        error: error.to_string(),
+        timestamp: Utc::now(),
+    };
+
+    // fall back to the source position so dead letters are still traceable without a key
+    let dead_letter_key = key.unwrap_or_else(|| {
+        format!(
+            "{}-{}-{}",
+            dead_letter.topic, dead_letter.partition, dead_letter.offset
+        )
+    });
+
+    publish_reply(
+        producer,
+        DEAD_LETTER_TOPIC,
+        &dead_letter_key,
+        dead_letter.correlation_id.as_deref(),
+        &dead_letter,
+    )
+    .await
 }
@@ -1,80 +1,399 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use arc_swap::ArcSwap;
-use rust_decimal_macros::dec;
+use chrono::Utc;
 use tokio::sync::{mpsc, oneshot};
 
 use futures::StreamExt;
-use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::producer::FutureProducer;
 use rdkafka::{ClientConfig, Message};
+use rdkafka::{Offset, TopicPartitionList};
 
-use crate::kafka::handle_kafka_message;
+use crate::events::{run_publisher, RedisPublisher};
+use crate::journal::{Journal, JournalHandle, JournalRecord};
+use crate::kafka::{
+    handle_kafka_message, publish_dead_letter, publish_reply, ConsumerOffsets, HandledRequests,
+    INPUT_TOPIC, ORDER_RESPONSES_TOPIC, USER_RESPONSES_TOPIC,
+};
+use crate::snapshot::Snapshot;
+use crate::storage::{Storage, StorageHandle, StoredState};
 use crate::types::types::KafkaMessages;
 use crate::types::{
-    positions::Positions,
-    types::{CurrentPrice, PositionManagerMsg, UserManagerMsg, WalletManagerMsg},
+    assets::AssetRegistry,
+    orders::{OrderStatus, PendingOrder},
+    positions::{
+        ClosedPosition, MarginMode, Position, PositionChanges, PositionMode, Positions, RiskEvent,
+    },
+    prices::PriceStore,
+    types::{
+        CancelOrderResponse, CloseOrderResponse, CreateUserMessage, CreateUserResponse,
+        DepositResponse, GetListResponse, LedgerSnapshot, ModifyPositionResponse,
+        OpenOrderResponse, PositionManagerMsg, SetMarginModeResponse, SetPositionModeResponse,
+        SetTargetsResponse, UserManagerMsg, WalletManagerMsg,
+    },
     users::Users,
-    wallet::Wallets,
+    wallet::{Balance, Wallet, WalletError, Wallets},
 };
 
+mod events;
+mod journal;
 mod kafka;
+mod snapshot;
+mod storage;
 mod types;
 
 #[tokio::main]
 async fn main() {
-    let latest_price = Arc::new(ArcSwap::from(Arc::new(CurrentPrice {
-        bid: dec!(0),
-        ask: dec!(0),
-    })));
+    // `trading-backend --replay <journal dir>` rebuilds state from a journal, prints it and exits
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--replay") {
+        let journal_dir = args
+            .get(2)
+            .expect("usage: trading-backend --replay <journal dir>");
+        let replayed = journal::replay(
+            journal_dir,
+            StoredState::default(),
+            0,
+            ConsumerOffsets::default(),
+        )
+        .expect("Journal replay failed");
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&replayed.state).expect("Could not serialize state")
+        );
+        return;
+    }
 
-    let mut users: Users = Users::new();
-    let wallets: Wallets = Wallets::new();
-    let mut positions: Positions = Positions::new(latest_price.clone());
+    let price_store = Arc::new(PriceStore::new());
 
-    let (user_tx, mut user_rx) = mpsc::unbounded_channel::<UserManagerMsg>();
-    let (wallet_tx, mut wallet_rx) = mpsc::unbounded_channel::<WalletManagerMsg>();
-    let (position_tx, mut position_rx) = mpsc::unbounded_channel::<PositionManagerMsg>();
+    let snapshot_dir = std::env::var("SNAPSHOT_DIR").ok().map(PathBuf::from);
+    let snapshot_interval = std::env::var("SNAPSHOT_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(60));
+
+    let latest_snapshot = match &snapshot_dir {
+        Some(dir) => snapshot::load_latest(dir).expect("Could not read snapshots"),
+        None => None,
+    };
 
     let consumer: StreamConsumer = ClientConfig::new()
         .set("bootstrap.servers", "localhost:9092")
         .set("group.id", "rust-analyzer")
         .set("auto.offset.reset", "earliest")
+        // offsets are committed by hand once a message's effects are persisted
+        .set("enable.auto.commit", "false")
         .create()
         .expect("Consumer creation failed");
 
+    // without DATABASE_URL the engine runs purely in memory
+    let (storage_handle, stored_state, database_enabled) = match std::env::var("DATABASE_URL") {
+        Ok(database_url) => {
+            let storage = Storage::connect(&database_url)
+                .await
+                .expect("Database connection failed");
+            let stored_state = storage
+                .load_state()
+                .await
+                .expect("Could not load state from database");
+            (storage.spawn_writer(), stored_state, true)
+        }
+        Err(_) => {
+            eprintln!("[STORAGE] DATABASE_URL not set, state will not survive a restart");
+            (StorageHandle::disabled(), StoredState::default(), false)
+        }
+    };
+
+    // the newest snapshot plus the journal after it wins when configured, and consumption
+    // resumes right after the last message it reflects. A journal turned on after the database,
+    // or one that stopped while the database kept being written, is behind the group's
+    // committed offsets; the database is the newer state then
+    let (journal_handle, stored_state, mut consumer_offsets) = match std::env::var("JOURNAL_DIR") {
+        Ok(journal_dir) => {
+            let has_snapshot = latest_snapshot.is_some();
+            let (base_state, base_seq, base_offsets) = match latest_snapshot {
+                Some(snapshot) => (snapshot.state, snapshot.journal_seq, snapshot.offsets),
+                None => (StoredState::default(), 0, ConsumerOffsets::default()),
+            };
+            let replayed = journal::replay(&journal_dir, base_state, base_seq, base_offsets)
+                .expect("Journal replay failed");
+            eprintln!("[JOURNAL] replayed up to seq {}", replayed.last_seq);
+            let journal = Journal::open(&journal_dir).expect("Could not open journal");
+
+            let committed = if database_enabled {
+                kafka::committed_offsets(&consumer).expect("Could not fetch committed offsets")
+            } else {
+                ConsumerOffsets::default()
+            };
+            let journal_is_new = !has_snapshot && replayed.last_seq == 0;
+
+            if database_enabled && (journal_is_new || replayed.offsets.is_behind(&committed)) {
+                eprintln!("[JOURNAL] journal is behind the database, starting from the database");
+
+                // replay can only rebuild this state on top of a snapshot of it; everything
+                // else in the database belongs to a user, so an empty one needs no snapshot
+                let stored_state = if stored_state.users.is_empty() {
+                    stored_state
+                } else {
+                    let dir = snapshot_dir
+                        .as_ref()
+                        .expect("SNAPSHOT_DIR must be set to start a journal from the database");
+                    let base = Snapshot {
+                        taken_at: Utc::now(),
+                        journal_seq: replayed.last_seq,
+                        offsets: committed.clone(),
+                        state: stored_state,
+                    };
+                    snapshot::write(dir, &base).expect("Could not write base snapshot");
+                    base.state
+                };
+
+                (journal.spawn_writer(), stored_state, committed)
+            } else {
+                (journal.spawn_writer(), replayed.state, replayed.offsets)
+            }
+        }
+        // without a journal a snapshot can be older than the database, which keeps being
+        // written after it, so the database and the committed group offsets win
+        Err(_) => match latest_snapshot {
+            Some(snapshot) if !database_enabled => {
+                (JournalHandle::disabled(), snapshot.state, snapshot.offsets)
+            }
+            Some(_) => {
+                eprintln!(
+                    "[SNAPSHOT] JOURNAL_DIR not set, ignoring snapshot in favour of the database"
+                );
+                (
+                    JournalHandle::disabled(),
+                    stored_state,
+                    ConsumerOffsets::default(),
+                )
+            }
+            None => (
+                JournalHandle::disabled(),
+                stored_state,
+                ConsumerOffsets::default(),
+            ),
+        },
+    };
+    let storage_handle = storage_handle.with_journal(journal_handle.clone());
+    let storage_handle_ = storage_handle.clone();
+    let journal_handle_ = journal_handle.clone();
+
+    println!(
+        "Hydrated {} users, {} wallets, {} open positions",
+        stored_state.users.len(),
+        stored_state.wallets.len(),
+        stored_state.positions.len()
+    );
+
+    let mut users: Users = Users::new(storage_handle.clone());
+    let mut wallets: Wallets = Wallets::new(storage_handle.clone());
+    let mut positions: Positions = Positions::new(
+        price_store.clone(),
+        AssetRegistry::with_defaults(),
+        storage_handle,
+    );
+
+    users.hydrate(stored_state.users);
+    wallets.hydrate(stored_state.wallets);
+    positions.hydrate(stored_state.positions);
+    positions.hydrate_margin_modes(stored_state.margin_modes);
+    positions.hydrate_position_modes(stored_state.position_modes);
+    positions.hydrate_resting_orders(stored_state.resting_orders);
+    positions.hydrate_netted_orders(stored_state.netted_orders);
+
+    let mut handled_requests = HandledRequests::default();
+    handled_requests.hydrate(stored_state.handled_requests);
+
+    let (user_tx, mut user_rx) = mpsc::unbounded_channel::<UserManagerMsg>();
+    let (wallet_tx, mut wallet_rx) = mpsc::unbounded_channel::<WalletManagerMsg>();
+    let (position_tx, mut position_rx) = mpsc::unbounded_channel::<PositionManagerMsg>();
+    let (event_tx, event_rx) = mpsc::unbounded_channel::<Vec<RiskEvent>>();
+
+    let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let publisher = RedisPublisher::new(&redis_url).expect("Invalid REDIS_URL");
+    tokio::task::spawn_blocking(move || run_publisher(publisher, event_rx));
+
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", "localhost:9092")
+        .create()
+        .expect("Producer creation failed");
+
     //  HACK:
     let wallet_tx_ = wallet_tx.clone();
 
-    tokio::spawn(async move {
+    // RISK_TICK_INTERVAL_MS runs risk passes on a fixed timer instead of on every price update
+    let risk_tick_interval = std::env::var("RISK_TICK_INTERVAL_MS")
+        .ok()
+        .and_then(|ms| ms.parse::<u64>().ok())
+        .map(Duration::from_millis);
+
+    // set while an UpdateRisk is queued so slow passes don't pile up behind price ticks
+    let risk_pass_pending = Arc::new(AtomicBool::new(false));
+    let risk_pass_pending_ = risk_pass_pending.clone();
+
+    if let Some(interval) = risk_tick_interval {
+        let position_tx = position_tx.clone();
+        let risk_pass_pending = risk_pass_pending.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if !risk_pass_pending.swap(true, Ordering::AcqRel)
+                    && position_tx.send(PositionManagerMsg::UpdateRisk).is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    if consumer_offsets.is_empty() {
+        consumer.subscribe(&[INPUT_TOPIC]).expect("Can't subscribe");
+    } else {
+        // resume exactly where the restored state left off instead of the group's committed offsets
+        let metadata = consumer
+            .fetch_metadata(Some(INPUT_TOPIC), Duration::from_secs(10))
+            .expect("Could not fetch topic metadata");
+        let mut assignment = TopicPartitionList::new();
+        for topic in metadata.topics() {
+            for partition in topic.partitions() {
+                let offset = consumer_offsets
+                    .next_offset(INPUT_TOPIC, partition.id())
+                    .map(Offset::Offset)
+                    .unwrap_or(Offset::Beginning);
+                assignment
+                    .add_partition_offset(INPUT_TOPIC, partition.id(), offset)
+                    .expect("Invalid partition offset");
+            }
+        }
         consumer
-            .subscribe(&["priceUpdate"])
-            .expect("Can't subscribe");
+            .assign(&assignment)
+            .expect("Can't assign partitions");
+    }
 
+    let user_tx_ = user_tx.clone();
+    let position_tx_ = position_tx.clone();
+    let deposit_wallet_tx = wallet_tx.clone();
+
+    tokio::spawn(async move {
         println!("Consumer started");
 
+        let mut snapshot_ticker = snapshot_dir
+            .as_ref()
+            .map(|_| snapshot::ticker(snapshot_interval));
+
         let mut stream = consumer.stream();
-        while let Some(message) = stream.next().await {
+        loop {
+            let message = tokio::select! {
+                message = stream.next() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = snapshot::next_tick(&mut snapshot_ticker) => {
+                    if let Some(dir) = &snapshot_dir {
+                        let written = snapshot::take_and_write(
+                            dir,
+                            &user_tx_,
+                            &position_tx_,
+                            &consumer_offsets,
+                            &handled_requests,
+                        )
+                        .await;
+                        match written {
+                            Ok(path) => println!("[SNAPSHOT] wrote {:?}", path),
+                            Err(err) => eprintln!("[SNAPSHOT] {}", err),
+                        }
+                    }
+                    continue;
+                }
+            };
+
             match message {
                 Ok(m) => {
-                    let payload = match m.payload_view::<str>() {
-                        Some(Ok(s)) => s,
-                        _ => "",
+                    let incoming = match handle_kafka_message(m.payload()) {
+                        Ok(incoming) => incoming,
+                        Err(err) => {
+                            eprintln!(
+                                "[KAFKA CONSUMER] bad message at offset {}: {}",
+                                m.offset(),
+                                err
+                            );
+                            // committing without the dead letter would lose the message, so
+                            // exit like a failed flush and let the restart redeliver it
+                            if let Err(err) = publish_dead_letter(&producer, &m, &err).await {
+                                eprintln!(
+                                    "[KAFKA DEAD LETTER] not committing {}, exiting: {}",
+                                    m.offset(),
+                                    err
+                                );
+                                std::process::exit(1);
+                            }
+                            consumer_offsets.record(m.topic(), m.partition(), m.offset());
+                            if let Err(err) = consumer.commit_message(&m, CommitMode::Async) {
+                                eprintln!("[KAFKA COMMIT] {}", err);
+                            }
+                            continue;
+                        }
                     };
-                    let key = m.key().map(|k| String::from_utf8_lossy(k).to_string());
 
-                    if let Some(key) = key {
-                        let parsed_message = handle_kafka_message(key.as_str(), payload);
+                    // journaled after the command completes so replay never skips one whose
+                    // state changes didn't make it to disk; a redelivered order is a no-op
+                    let command = journal_handle
+                        .is_enabled()
+                        .then(|| incoming.message.clone());
+                    let mutates_state = !matches!(
+                        incoming.message,
+                        KafkaMessages::IncomingPrices(_) | KafkaMessages::ListPositions(_)
+                    );
 
-                        match parsed_message {
-                            KafkaMessages::IncomingPrices(price) => {}
+                    // a redelivered command that moves money gets the reply it already produced
+                    let handled = incoming
+                        .message
+                        .request_id()
+                        .and_then(|request_id| handled_requests.get(request_id))
+                        .cloned();
+
+                    if let Some(handled) = handled {
+                        if let Err(err) = publish_reply(
+                            &producer,
+                            &handled.topic,
+                            &handled.request_id,
+                            incoming.correlation_id.as_deref(),
+                            &handled.response,
+                        )
+                        .await
+                        {
+                            eprintln!("[KAFKA HANDLED RESPONSE] {}", err);
+                        }
+                    } else {
+                        match incoming.message {
+                            KafkaMessages::IncomingPrices(prices) => {
+                                price_store.update(prices, incoming.timestamp);
+
+                                if risk_tick_interval.is_none()
+                                    && !risk_pass_pending.swap(true, Ordering::AcqRel)
+                                {
+                                    if let Err(err) =
+                                        position_tx.send(PositionManagerMsg::UpdateRisk)
+                                    {
+                                        eprintln!("[KAFKA CONSUMER PRICE] {}", err);
+                                    }
+                                }
+                            }
                             KafkaMessages::Order(order) => {
-                                println!("{:?}", order);
+                                let order_id = order.order_id.clone();
                                 let (oneshot_tx, oneshot_rx) =
-                                    oneshot::channel::<Result<String, String>>();
+                                    oneshot::channel::<Result<OrderStatus, String>>();
 
                                 let sent = position_tx.send(PositionManagerMsg::Open {
                                     user_id: order.user_id.clone(),
-                                    order: order,
+                                    order,
                                     responder: oneshot_tx,
                                 });
 
@@ -82,24 +401,430 @@ async fn main() {
                                     eprintln!("[KAFKA CONSUMER ORDER] {}", err);
                                 }
 
-                                match oneshot_rx.await {
-                                    Ok(Ok(response)) => {
-                                        println!("{}", response);
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER ORDER] {}", err);
+                                        Err("Could not process order, server error".to_string())
+                                    }
+                                };
+
+                                let response = OpenOrderResponse::new(order_id.clone(), result);
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &order_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA ORDER RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::CancelOrder(cancel) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<PendingOrder, String>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::Cancel {
+                                    user_id: cancel.user_id,
+                                    order_id: cancel.order_id.clone(),
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER CANCEL ORDER] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER CANCEL ORDER] {}", err);
+                                        Err("Could not cancel order, server error".to_string())
                                     }
-                                    Ok(Err(err)) => {
-                                        println!("{}", err);
+                                };
+
+                                let response = CancelOrderResponse::new(
+                                    cancel.request_id.clone(),
+                                    cancel.order_id.clone(),
+                                    result,
+                                );
+                                handled_requests.record(
+                                    &storage_handle_,
+                                    &cancel.request_id,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &response,
+                                );
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &cancel.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA CANCEL ORDER RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::CloseOrder(close) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<ClosedPosition, String>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::Close {
+                                    user_id: close.user_id,
+                                    position_id: close.order_id.clone(),
+                                    qty: close.qty,
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER CLOSE ORDER] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER CLOSE ORDER] {}", err);
+                                        Err("Could not close position, server error".to_string())
                                     }
+                                };
+
+                                let response = CloseOrderResponse::new(
+                                    close.request_id.clone(),
+                                    close.order_id.clone(),
+                                    result,
+                                );
+                                handled_requests.record(
+                                    &storage_handle_,
+                                    &close.request_id,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &response,
+                                );
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &close.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA CLOSE ORDER RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::ListPositions(list) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Option<Vec<Position>>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::List {
+                                    user_id: list.user_id,
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER LIST POSITIONS] {}", err);
+                                }
+
+                                // users that never opened a position have no entry yet
+                                let positions = match oneshot_rx.await {
+                                    Ok(positions) => positions.unwrap_or_default(),
                                     Err(err) => {
-                                        eprintln!("{}", err);
+                                        eprintln!("[KAFKA CONSUMER LIST POSITIONS] {}", err);
+                                        Vec::new()
                                     }
+                                };
+
+                                let response = GetListResponse {
+                                    request_id: list.request_id.clone(),
+                                    positions,
+                                };
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &list.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA LIST POSITIONS RESPONSE] {}", err);
                                 }
                             }
                             KafkaMessages::CreateUser(signup_req) => {
-                                signup_req.email;
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<String, String>>();
+
+                                let sent =
+                                    user_tx.send(UserManagerMsg::Create(CreateUserMessage {
+                                        email: signup_req.email,
+                                        responder: oneshot_tx,
+                                    }));
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER CREATE USER] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER CREATE USER] {}", err);
+                                        Err("Could not create user, server error".to_string())
+                                    }
+                                };
+
+                                let response =
+                                    CreateUserResponse::new(signup_req.request_id.clone(), result);
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    USER_RESPONSES_TOPIC,
+                                    &signup_req.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA CREATE USER RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::Deposit(request) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<Balance, WalletError>>();
+
+                                let sent = deposit_wallet_tx.send(WalletManagerMsg::Deposit {
+                                    user_id: request.user_id,
+                                    currency: request.currency.clone(),
+                                    amount: request.amount,
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER DEPOSIT] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result.map_err(String::from),
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER DEPOSIT] {}", err);
+                                        Err("Could not deposit, server error".to_string())
+                                    }
+                                };
+
+                                let response = DepositResponse::new(
+                                    request.request_id.clone(),
+                                    request.currency,
+                                    result,
+                                );
+                                handled_requests.record(
+                                    &storage_handle_,
+                                    &request.request_id,
+                                    USER_RESPONSES_TOPIC,
+                                    &response,
+                                );
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    USER_RESPONSES_TOPIC,
+                                    &request.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA DEPOSIT RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::SetMarginMode(request) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<MarginMode, String>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::SetMarginMode {
+                                    user_id: request.user_id,
+                                    mode: request.mode,
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER SET MARGIN MODE] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER SET MARGIN MODE] {}", err);
+                                        Err("Could not set margin mode, server error".to_string())
+                                    }
+                                };
+
+                                let response =
+                                    SetMarginModeResponse::new(request.request_id.clone(), result);
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    USER_RESPONSES_TOPIC,
+                                    &request.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA SET MARGIN MODE RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::SetPositionMode(request) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<PositionMode, String>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::SetPositionMode {
+                                    user_id: request.user_id,
+                                    mode: request.mode,
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER SET POSITION MODE] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER SET POSITION MODE] {}", err);
+                                        Err("Could not set position mode, server error".to_string())
+                                    }
+                                };
+
+                                let response = SetPositionModeResponse::new(
+                                    request.request_id.clone(),
+                                    result,
+                                );
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    USER_RESPONSES_TOPIC,
+                                    &request.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA SET POSITION MODE RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::SetTargets(request) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<Position, String>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::SetTargets {
+                                    user_id: request.user_id,
+                                    position_id: request.position_id,
+                                    target_mode: request.target_mode,
+                                    stop_loss: request.stop_loss,
+                                    take_profit: request.take_profit,
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER SET TARGETS] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER SET TARGETS] {}", err);
+                                        Err("Could not set targets, server error".to_string())
+                                    }
+                                };
+
+                                let response =
+                                    SetTargetsResponse::new(request.request_id.clone(), result);
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &request.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA SET TARGETS RESPONSE] {}", err);
+                                }
+                            }
+                            KafkaMessages::ModifyPosition(request) => {
+                                let (oneshot_tx, oneshot_rx) =
+                                    oneshot::channel::<Result<Position, String>>();
+
+                                let sent = position_tx.send(PositionManagerMsg::Modify {
+                                    user_id: request.user_id,
+                                    position_id: request.position_id,
+                                    changes: PositionChanges {
+                                        target_mode: request.target_mode,
+                                        stop_loss: request.stop_loss,
+                                        take_profit: request.take_profit,
+                                        clear_stop_loss: request.clear_stop_loss,
+                                        clear_take_profit: request.clear_take_profit,
+                                        margin_delta: request.margin_delta,
+                                    },
+                                    responder: oneshot_tx,
+                                });
+
+                                if let Err(err) = sent {
+                                    eprintln!("[KAFKA CONSUMER MODIFY POSITION] {}", err);
+                                }
+
+                                let result = match oneshot_rx.await {
+                                    Ok(result) => result,
+                                    Err(err) => {
+                                        eprintln!("[KAFKA CONSUMER MODIFY POSITION] {}", err);
+                                        Err("Could not modify position, server error".to_string())
+                                    }
+                                };
+
+                                let response =
+                                    ModifyPositionResponse::new(request.request_id.clone(), result);
+                                handled_requests.record(
+                                    &storage_handle_,
+                                    &request.request_id,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &response,
+                                );
+                                if let Err(err) = publish_reply(
+                                    &producer,
+                                    ORDER_RESPONSES_TOPIC,
+                                    &request.request_id,
+                                    incoming.correlation_id.as_deref(),
+                                    &response,
+                                )
+                                .await
+                                {
+                                    eprintln!("[KAFKA MODIFY POSITION RESPONSE] {}", err);
+                                }
                             }
-                            KafkaMessages::InvalidMessage => {}
                         }
                     }
+
+                    if let Some(message) = command {
+                        journal_handle.record(JournalRecord::Command {
+                            topic: m.topic().to_string(),
+                            partition: m.partition(),
+                            offset: m.offset(),
+                            message: Box::new(message),
+                        });
+                    }
+                    consumer_offsets.record(m.topic(), m.partition(), m.offset());
+
+                    // a crash before this commit redelivers the message, it is never lost. A later
+                    // commit would cover this offset too, so the engine exits and the restart
+                    // redelivers it
+                    if mutates_state {
+                        if let Err(err) = storage_handle_.flush().await {
+                            eprintln!(
+                                "[KAFKA COMMIT] not committing {}, exiting: {}",
+                                m.offset(),
+                                err
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                    if let Err(err) = consumer.commit_message(&m, CommitMode::Async) {
+                        eprintln!("[KAFKA COMMIT] {}", err);
+                    }
                 }
                 Err(e) => eprintln!("Kafka error: {}", e),
             }
@@ -112,88 +837,144 @@ async fn main() {
             match msg {
                 UserManagerMsg::Create(create_msg) => {
                     let sent = match users
-                        .create_user(create_msg.username, wallet_tx_.clone())
+                        .create_user(create_msg.email, wallet_tx_.clone())
                         .await
                     {
                         Ok(user_id) => create_msg.responder.send(Ok(user_id)),
                         Err(err) => create_msg.responder.send(Err(err)),
                     };
 
-                    if let Err(_) = sent {
+                    if sent.is_err() {
                         eprintln!("[error responding to create user message]");
                     }
                 }
+                UserManagerMsg::Snapshot { responder } => {
+                    if responder.send(users.snapshot()).is_err() {
+                        eprintln!("[error responding to user snapshot message]");
+                    }
+                }
             };
         }
     });
 
     // Manages wallet
     tokio::spawn(async move {
-        let mut wallets = wallets;
         while let Some(msg) = wallet_rx.recv().await {
             match msg {
                 WalletManagerMsg::Credit {
                     user_id,
+                    currency,
                     amount,
                     responder,
-                } => match wallets.get_balance(&user_id) {
-                    Some(current_balance) => {
-                        if let Err(err) = wallets.update_balance(user_id, current_balance + amount)
-                        {
-                            eprintln!("{}", err);
-                        }
-                        if let Err(_) = responder.send(Ok(())) {
-                            eprintln!("[ERROR] wallet oneshot channel closed");
-                        }
-                    }
-                    None => {
-                        if let Err(_) = responder.send(Err("Could not find wallet".to_string())) {
-                            eprintln!("[ERROR] wallet oneshot channel closed");
-                        }
+                } => {
+                    if responder
+                        .send(wallets.credit(&user_id, &currency, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
                     }
-                },
+                }
                 WalletManagerMsg::Debit {
                     user_id,
+                    currency,
                     amount,
                     responder,
-                } => match wallets.get_balance(&user_id) {
-                    Some(current_balance) => {
-                        if let Err(err) = wallets.update_balance(user_id, current_balance - amount)
-                        {
-                            eprintln!("{}", err);
-                        }
-                        if let Err(_) = responder.send(Ok(())) {
-                            eprintln!("[ERROR] wallet oneshot channel closed");
-                        }
+                } => {
+                    if responder
+                        .send(wallets.debit(&user_id, &currency, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
                     }
-                    None => {
-                        if let Err(_) = responder.send(Err("Could not find wallet".to_string())) {
-                            eprintln!("[ERROR] wallet oneshot channel closed");
-                        }
+                }
+                WalletManagerMsg::TryDebit {
+                    user_id,
+                    currency,
+                    amount,
+                    responder,
+                } => {
+                    if responder
+                        .send(wallets.try_debit(&user_id, &currency, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
                     }
-                },
+                }
+                WalletManagerMsg::Reserve {
+                    user_id,
+                    currency,
+                    amount,
+                    responder,
+                } => {
+                    if responder
+                        .send(wallets.reserve(&user_id, &currency, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
+                    }
+                }
+                WalletManagerMsg::Release {
+                    user_id,
+                    currency,
+                    amount,
+                    responder,
+                } => {
+                    if responder
+                        .send(wallets.release(&user_id, &currency, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
+                    }
+                }
+                WalletManagerMsg::Commit {
+                    user_id,
+                    currency,
+                    reserved,
+                    amount,
+                    responder,
+                } => {
+                    if responder
+                        .send(wallets.commit(&user_id, &currency, reserved, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
+                    }
+                }
                 WalletManagerMsg::GetBalance { user_id, responder } => {
-                    let sent = match wallets.get_balance(&user_id) {
-                        Some(balance) => responder.send(Some(balance)),
-                        None => responder.send(None),
-                    };
-
-                    if let Err(_) = sent {
+                    if responder.send(wallets.get(&user_id)).is_err() {
                         println!("[ERROR RESPONDING BACK TO GET BALANCE]");
                     }
                 }
+                WalletManagerMsg::Deposit {
+                    user_id,
+                    currency,
+                    amount,
+                    responder,
+                } => {
+                    if responder
+                        .send(wallets.deposit(&user_id, &currency, amount))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR] wallet oneshot channel closed");
+                    }
+                }
                 WalletManagerMsg::Create { user_id, responder } => match wallets.create(user_id) {
                     Ok(_) => {
-                        if let Err(_) = responder.send(Ok(())) {
+                        if responder.send(Ok(())).is_err() {
                             eprintln!("[ERROR] responder connection closed");
                         }
                     }
                     Err(err) => {
-                        if let Err(_) = responder.send(Err(err)) {
+                        if responder.send(Err(err)).is_err() {
                             eprintln!("[ERROR] responder connection closed");
                         }
                     }
                 },
+                WalletManagerMsg::Snapshot { responder } => {
+                    if responder.send(wallets.snapshot()).is_err() {
+                        eprintln!("[ERROR] responder connection closed");
+                    }
+                }
             }
         }
     });
@@ -208,28 +989,42 @@ async fn main() {
                     responder,
                 } => {
                     let sent = match positions.open(user_id, order, wallet_tx.clone()).await {
-                        Ok(position_id) => responder.send(Ok(position_id)),
+                        Ok(position) => responder.send(Ok(position)),
                         Err(err) => responder.send(Err(err)),
                     };
 
-                    if let Err(_) = sent {
+                    if sent.is_err() {
                         eprintln!("[ERROR RESPONDING TO POSITION OPEN MSG]");
                     }
                 }
+                PositionManagerMsg::Cancel {
+                    user_id,
+                    order_id,
+                    responder,
+                } => {
+                    let result = positions
+                        .cancel(&user_id, &order_id, wallet_tx.clone())
+                        .await;
+
+                    if responder.send(result).is_err() {
+                        eprintln!("[ERROR RESPONDING TO ORDER CANCEL MSG]");
+                    }
+                }
                 PositionManagerMsg::Close {
                     user_id,
                     position_id,
+                    qty,
                     responder,
                 } => {
                     let sent = match positions
-                        .close(&user_id, position_id, wallet_tx.clone())
+                        .close(&user_id, position_id, qty, wallet_tx.clone())
                         .await
                     {
-                        Ok(_) => responder.send(Ok(())),
+                        Ok(closed) => responder.send(Ok(closed)),
                         Err(err) => responder.send(Err(err)),
                     };
 
-                    if let Err(_) = sent {
+                    if sent.is_err() {
                         eprintln!("[ERROR RESPONDING TO POSITION CLOSE MSG]");
                     }
                 }
@@ -238,19 +1033,107 @@ async fn main() {
                         Ok(positions_list) => responder.send(Some(positions_list)),
                         Err(_) => responder.send(None),
                     };
-                    if let Err(_) = sent {
+                    if sent.is_err() {
                         eprintln!("[ERROR RESPONDING TO POSITION LIST MSG]")
                     }
                 }
+                PositionManagerMsg::SetMarginMode {
+                    user_id,
+                    mode,
+                    responder,
+                } => {
+                    if responder
+                        .send(positions.set_margin_mode(user_id, mode))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR RESPONDING TO SET MARGIN MODE MSG]");
+                    }
+                }
+                PositionManagerMsg::SetPositionMode {
+                    user_id,
+                    mode,
+                    responder,
+                } => {
+                    if responder
+                        .send(positions.set_position_mode(user_id, mode))
+                        .is_err()
+                    {
+                        eprintln!("[ERROR RESPONDING TO SET POSITION MODE MSG]");
+                    }
+                }
+                PositionManagerMsg::SetTargets {
+                    user_id,
+                    position_id,
+                    target_mode,
+                    stop_loss,
+                    take_profit,
+                    responder,
+                } => {
+                    let result = positions
+                        .set_targets(
+                            user_id,
+                            position_id,
+                            target_mode,
+                            stop_loss,
+                            take_profit,
+                            wallet_tx.clone(),
+                        )
+                        .await;
+                    if responder.send(result).is_err() {
+                        eprintln!("[ERROR RESPONDING TO SET TARGETS MSG]");
+                    }
+                }
+                PositionManagerMsg::Modify {
+                    user_id,
+                    position_id,
+                    changes,
+                    responder,
+                } => {
+                    let result = positions
+                        .modify(user_id, position_id, changes, wallet_tx.clone())
+                        .await;
+                    if responder.send(result).is_err() {
+                        eprintln!("[ERROR RESPONDING TO MODIFY POSITION MSG]");
+                    }
+                }
                 PositionManagerMsg::UpdateRisk => {
-                    // match positions.update_risk(wallet_tx.clone()).await {
-                    //     Ok(_) => {}
-                    //     Err(_) => {
-                    //         eprintln!("[UDPATE RISK PANIC]");
-                    //     }
-                    // }
-                    // let prices = latest_price_.clone().load();
-                    // println!("{} | {}", prices.bid, prices.ask);
+                    risk_pass_pending_.store(false, Ordering::Release);
+
+                    positions.fill_resting(wallet_tx.clone()).await;
+                    let events = positions.update_risk(wallet_tx.clone()).await;
+                    if !events.is_empty() && event_tx.send(events).is_err() {
+                        eprintln!("[ERROR] event publisher channel closed");
+                    }
+                }
+                PositionManagerMsg::Snapshot { responder } => {
+                    // gathered here so no risk pass can move money between the two reads
+                    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Vec<Wallet>>();
+                    let wallets = match wallet_tx.send(WalletManagerMsg::Snapshot {
+                        responder: oneshot_tx,
+                    }) {
+                        Ok(_) => oneshot_rx.await.map_err(|err| err.to_string()),
+                        Err(err) => Err(err.to_string()),
+                    };
+
+                    let ledger = match wallets {
+                        Ok(wallets) => match journal_handle_.checkpoint().await {
+                            Ok(journal_seq) => Ok(LedgerSnapshot {
+                                wallets,
+                                positions: positions.snapshot(),
+                                margin_modes: positions.margin_modes_snapshot(),
+                                position_modes: positions.position_modes_snapshot(),
+                                resting_orders: positions.resting_orders_snapshot(),
+                                netted_orders: positions.netted_orders_snapshot(),
+                                journal_seq,
+                            }),
+                            Err(err) => Err(err),
+                        },
+                        Err(err) => Err(err),
+                    };
+
+                    if responder.send(ledger).is_err() {
+                        eprintln!("[ERROR RESPONDING TO POSITION SNAPSHOT MSG]");
+                    }
                 }
             }
         }
@@ -0,0 +1,172 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+use tokio::time::{Instant, Interval};
+
+use crate::kafka::{ConsumerOffsets, HandledRequests};
+use crate::storage::StoredState;
+use crate::types::{
+    types::{LedgerSnapshot, PositionManagerMsg, UserManagerMsg},
+    users::User,
+};
+
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+const SNAPSHOTS_TO_KEEP: usize = 3;
+
+/// Full engine state at a point where no command was in flight.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub taken_at: DateTime<Utc>,
+    // last journal entry reflected in `state`, replay resumes after it
+    pub journal_seq: u64,
+    pub offsets: ConsumerOffsets,
+    pub state: StoredState,
+}
+
+/// Collects state from the actors. Must be called from the consumer between messages so
+/// no command is half applied; the position actor gathers wallets and the journal seq
+/// itself so a risk pass can't land in between.
+pub async fn take(
+    user_tx: &UnboundedSender<UserManagerMsg>,
+    position_tx: &UnboundedSender<PositionManagerMsg>,
+    offsets: &ConsumerOffsets,
+    handled_requests: &HandledRequests,
+) -> Result<Snapshot, String> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<LedgerSnapshot, String>>();
+    position_tx
+        .send(PositionManagerMsg::Snapshot {
+            responder: oneshot_tx,
+        })
+        .map_err(|err| err.to_string())?;
+    let ledger = oneshot_rx.await.map_err(|err| err.to_string())??;
+
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Vec<User>>();
+    user_tx
+        .send(UserManagerMsg::Snapshot {
+            responder: oneshot_tx,
+        })
+        .map_err(|err| err.to_string())?;
+    let users = oneshot_rx.await.map_err(|err| err.to_string())?;
+
+    Ok(Snapshot {
+        taken_at: Utc::now(),
+        journal_seq: ledger.journal_seq,
+        offsets: offsets.clone(),
+        state: StoredState {
+            users,
+            wallets: ledger.wallets,
+            positions: ledger.positions,
+            margin_modes: ledger.margin_modes,
+            position_modes: ledger.position_modes,
+            resting_orders: ledger.resting_orders,
+            netted_orders: ledger.netted_orders,
+            handled_requests: handled_requests.snapshot(),
+        },
+    })
+}
+
+/// Takes a snapshot and writes it to `dir` off the async runtime.
+pub async fn take_and_write(
+    dir: &Path,
+    user_tx: &UnboundedSender<UserManagerMsg>,
+    position_tx: &UnboundedSender<PositionManagerMsg>,
+    offsets: &ConsumerOffsets,
+    handled_requests: &HandledRequests,
+) -> Result<PathBuf, String> {
+    let snapshot = take(user_tx, position_tx, offsets, handled_requests).await?;
+    let dir = dir.to_path_buf();
+
+    tokio::task::spawn_blocking(move || write(&dir, &snapshot))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+pub fn ticker(period: Duration) -> Interval {
+    // skip the immediate first tick, there's nothing new to snapshot right after startup
+    tokio::time::interval_at(Instant::now() + period, period)
+}
+
+/// Waits for the next tick, or forever when snapshots are off.
+pub async fn next_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending::<()>().await,
+    }
+}
+
+/// Writes to a temp file and renames it into place, so a crash never leaves a partial snapshot.
+pub fn write(dir: &Path, snapshot: &Snapshot) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+
+    let name = format!("{:020}", snapshot.taken_at.timestamp_millis());
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    let path = dir.join(format!("{}.{}", name, SNAPSHOT_EXTENSION));
+
+    let file = File::create(&tmp_path).map_err(|err| err.to_string())?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, snapshot).map_err(|err| err.to_string())?;
+    writer.flush().map_err(|err| err.to_string())?;
+    writer.get_ref().sync_all().map_err(|err| err.to_string())?;
+
+    fs::rename(&tmp_path, &path).map_err(|err| err.to_string())?;
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|err| err.to_string())?;
+
+    let paths = snapshot_paths(dir)?;
+    for old in paths
+        .iter()
+        .take(paths.len().saturating_sub(SNAPSHOTS_TO_KEEP))
+    {
+        if let Err(err) = fs::remove_file(old) {
+            eprintln!("[SNAPSHOT] could not remove {:?}: {}", old, err);
+        }
+    }
+
+    Ok(path)
+}
+
+/// Newest snapshot in `dir` that can be read back, skipping any that are corrupt.
+pub fn load_latest(dir: &Path) -> Result<Option<Snapshot>, String> {
+    for path in snapshot_paths(dir)?.iter().rev() {
+        let snapshot = File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|file| {
+                serde_json::from_reader::<_, Snapshot>(BufReader::new(file))
+                    .map_err(|err| err.to_string())
+            });
+
+        match snapshot {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(err) => eprintln!("[SNAPSHOT] skipping {:?}: {}", path, err),
+        }
+    }
+
+    Ok(None)
+}
+
+fn snapshot_paths(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = fs::read_dir(dir)
+        .map_err(|err| err.to_string())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == SNAPSHOT_EXTENSION)
+        })
+        .collect::<Vec<PathBuf>>();
+
+    // zero padded timestamps sort oldest first
+    paths.sort();
+    Ok(paths)
+}
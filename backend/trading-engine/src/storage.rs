@@ -0,0 +1,599 @@
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, PgPool, Row};
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    oneshot,
+};
+
+use crate::journal::{JournalHandle, JournalRecord};
+use crate::kafka::HandledRequest;
+use crate::types::{
+    orders::{NettedOrder, PendingOrder},
+    positions::{ClosedPosition, MarginMode, Position, PositionMode, TargetMode},
+    users::User,
+    wallet::{Balance, Wallet},
+};
+
+/// State changes the actors hand off to be written behind to postgres and the journal.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum StorageMsg {
+    UpsertUser(User),
+    UpsertWallet(Wallet),
+    OpenPosition {
+        user_id: String,
+        position: Position,
+    },
+    ClosePosition {
+        user_id: String,
+        closed: ClosedPosition,
+    },
+    // a partial close, `closed.remaining` is what stays open
+    ReducePosition {
+        user_id: String,
+        closed: ClosedPosition,
+    },
+    PlaceOrder(PendingOrder),
+    // a resting order that filled or was cancelled, its reserve is already settled
+    RemoveOrder {
+        order_id: String,
+    },
+    SetMarginMode {
+        user_id: String,
+        mode: MarginMode,
+    },
+    SetPositionMode {
+        user_id: String,
+        mode: PositionMode,
+    },
+    NetOrder(NettedOrder),
+    HandleRequest(HandledRequest),
+}
+
+enum StorageCmd {
+    Apply(Box<StorageMsg>),
+    // replies once every change queued before it has been written, with the first write
+    // that failed since the last flush
+    Flush(oneshot::Sender<Result<(), String>>),
+}
+
+/// Cheap to clone sender for `StorageMsg`s, journals every change before handing it
+/// to the database writer. Either side is a no-op when not configured.
+#[derive(Clone, Debug)]
+pub struct StorageHandle {
+    storage_tx: Option<UnboundedSender<StorageCmd>>,
+    journal: JournalHandle,
+}
+
+impl StorageHandle {
+    pub fn disabled() -> StorageHandle {
+        StorageHandle {
+            storage_tx: None,
+            journal: JournalHandle::disabled(),
+        }
+    }
+
+    pub fn with_journal(self, journal: JournalHandle) -> StorageHandle {
+        StorageHandle { journal, ..self }
+    }
+
+    pub fn send(&self, msg: StorageMsg) {
+        if self.journal.is_enabled() {
+            self.journal
+                .record(JournalRecord::StateChange(Box::new(msg.clone())));
+        }
+
+        if let Some(storage_tx) = &self.storage_tx {
+            if storage_tx.send(StorageCmd::Apply(Box::new(msg))).is_err() {
+                eprintln!("[STORAGE] write-behind channel closed");
+            }
+        }
+    }
+
+    /// Waits until every change sent so far is durable in the journal and the database.
+    pub async fn flush(&self) -> Result<(), String> {
+        self.journal.checkpoint().await?;
+
+        if let Some(storage_tx) = &self.storage_tx {
+            let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), String>>();
+            storage_tx
+                .send(StorageCmd::Flush(oneshot_tx))
+                .map_err(|_| "write-behind channel closed".to_string())?;
+            oneshot_rx
+                .await
+                .map_err(|_| "write-behind task dropped flush".to_string())??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Everything needed to rebuild the in-memory stores on startup.
+#[derive(Serialize, Deserialize, Default)]
+pub struct StoredState {
+    pub users: Vec<User>,
+    pub wallets: Vec<Wallet>,
+    pub positions: Vec<(String, Position)>,
+    // users not listed are isolated
+    #[serde(default)]
+    pub margin_modes: Vec<(String, MarginMode)>,
+    // users not listed hold hedge-style positions
+    #[serde(default)]
+    pub position_modes: Vec<(String, PositionMode)>,
+    #[serde(default)]
+    pub resting_orders: Vec<PendingOrder>,
+    #[serde(default)]
+    pub netted_orders: Vec<NettedOrder>,
+    #[serde(default)]
+    pub handled_requests: Vec<HandledRequest>,
+}
+
+pub struct Storage {
+    pool: PgPool,
+}
+
+impl Storage {
+    pub async fn connect(database_url: &str) -> Result<Storage, String> {
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        Ok(Storage { pool })
+    }
+
+    pub async fn load_state(&self) -> Result<StoredState, String> {
+        Ok(StoredState {
+            users: self.load_users().await?,
+            wallets: self.load_wallets().await?,
+            positions: self.load_open_positions().await?,
+            margin_modes: self.load_margin_modes().await?,
+            position_modes: self.load_position_modes().await?,
+            resting_orders: self.load_resting_orders().await?,
+            netted_orders: self.load_netted_orders().await?,
+            handled_requests: self.load_handled_requests().await?,
+        })
+    }
+
+    pub async fn load_users(&self) -> Result<Vec<User>, String> {
+        let rows = sqlx::query("SELECT id, email FROM users")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(User {
+                    id: row.try_get("id")?,
+                    email: row.try_get("email")?,
+                })
+            })
+            .collect::<Result<Vec<User>, sqlx::Error>>()
+            .map_err(|err| err.to_string())
+    }
+
+    pub async fn load_wallets(&self) -> Result<Vec<Wallet>, String> {
+        let rows = sqlx::query(
+            "SELECT user_id, currency, available, locked FROM wallet_balances ORDER BY user_id",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        let mut wallets: Vec<Wallet> = Vec::new();
+        for row in rows.iter() {
+            let user_id: String = row.try_get("user_id").map_err(|err| err.to_string())?;
+            let currency: String = row.try_get("currency").map_err(|err| err.to_string())?;
+            let balance = Balance {
+                available: row.try_get("available").map_err(|err| err.to_string())?,
+                locked: row.try_get("locked").map_err(|err| err.to_string())?,
+            };
+
+            // rows come grouped by user, so a new user_id starts the next wallet
+            if wallets
+                .last()
+                .is_none_or(|wallet| wallet.user_id != user_id)
+            {
+                wallets.push(Wallet::new(user_id));
+            }
+            if let Some(wallet) = wallets.last_mut() {
+                wallet.balances.insert(currency, balance);
+            }
+        }
+
+        Ok(wallets)
+    }
+
+    /// Open positions as (user_id, position), pnl is left at zero until the next risk pass.
+    pub async fn load_open_positions(&self) -> Result<Vec<(String, Position)>, String> {
+        let rows = sqlx::query(
+            "SELECT position_id, user_id, asset, entry_price, qty, margin, stop_loss, take_profit, target_mode,
+                    leverage, liquidation_price, trailing_stop
+             FROM open_positions
+             ORDER BY opened_at",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                let target_mode: String = row.try_get("target_mode")?;
+                let trailing_stop: Option<String> = row.try_get("trailing_stop")?;
+                Ok((
+                    row.try_get("user_id")?,
+                    Position {
+                        position_id: row.try_get("position_id")?,
+                        asset: row.try_get("asset")?,
+                        entry_price: row.try_get("entry_price")?,
+                        qty: row.try_get("qty")?,
+                        pnl: dec!(0),
+                        margin: row.try_get("margin")?,
+                        stop_loss: row.try_get("stop_loss")?,
+                        take_profit: row.try_get("take_profit")?,
+                        target_mode: target_mode
+                            .parse::<TargetMode>()
+                            .map_err(|err| sqlx::Error::Decode(err.into()))?,
+                        leverage: row.try_get("leverage")?,
+                        liquidation_price: row.try_get("liquidation_price")?,
+                        trailing_stop: trailing_stop
+                            .map(|trailing_stop| serde_json::from_str(&trailing_stop))
+                            .transpose()
+                            .map_err(|err| sqlx::Error::Decode(Box::new(err)))?,
+                    },
+                ))
+            })
+            .collect::<Result<Vec<(String, Position)>, sqlx::Error>>()
+            .map_err(|err| err.to_string())
+    }
+
+    pub async fn load_margin_modes(&self) -> Result<Vec<(String, MarginMode)>, String> {
+        let rows = sqlx::query("SELECT user_id, mode FROM margin_modes")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                let user_id: String = row.try_get("user_id").map_err(|err| err.to_string())?;
+                let mode: String = row.try_get("mode").map_err(|err| err.to_string())?;
+                Ok((user_id, mode.parse::<MarginMode>()?))
+            })
+            .collect::<Result<Vec<(String, MarginMode)>, String>>()
+    }
+
+    pub async fn load_position_modes(&self) -> Result<Vec<(String, PositionMode)>, String> {
+        let rows = sqlx::query("SELECT user_id, mode FROM position_modes")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                let user_id: String = row.try_get("user_id").map_err(|err| err.to_string())?;
+                let mode: String = row.try_get("mode").map_err(|err| err.to_string())?;
+                Ok((user_id, mode.parse::<PositionMode>()?))
+            })
+            .collect::<Result<Vec<(String, PositionMode)>, String>>()
+    }
+
+    pub async fn load_resting_orders(&self) -> Result<Vec<PendingOrder>, String> {
+        let rows = sqlx::query(
+            "SELECT user_id, request, reserved, placed_at FROM resting_orders ORDER BY placed_at",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                let request: String = row.try_get("request").map_err(|err| err.to_string())?;
+                Ok(PendingOrder {
+                    user_id: row.try_get("user_id").map_err(|err| err.to_string())?,
+                    order: serde_json::from_str(&request).map_err(|err| err.to_string())?,
+                    reserved: row.try_get("reserved").map_err(|err| err.to_string())?,
+                    placed_at: row.try_get("placed_at").map_err(|err| err.to_string())?,
+                })
+            })
+            .collect::<Result<Vec<PendingOrder>, String>>()
+    }
+
+    pub async fn load_netted_orders(&self) -> Result<Vec<NettedOrder>, String> {
+        let rows = sqlx::query("SELECT order_id, user_id, position FROM netted_orders")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                let position: String = row.try_get("position").map_err(|err| err.to_string())?;
+                Ok(NettedOrder {
+                    user_id: row.try_get("user_id").map_err(|err| err.to_string())?,
+                    order_id: row.try_get("order_id").map_err(|err| err.to_string())?,
+                    position: serde_json::from_str(&position).map_err(|err| err.to_string())?,
+                })
+            })
+            .collect::<Result<Vec<NettedOrder>, String>>()
+    }
+
+    pub async fn load_handled_requests(&self) -> Result<Vec<HandledRequest>, String> {
+        let rows = sqlx::query("SELECT request_id, topic, response FROM handled_requests")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        rows.iter()
+            .map(|row| {
+                let response: String = row.try_get("response").map_err(|err| err.to_string())?;
+                Ok(HandledRequest {
+                    request_id: row.try_get("request_id").map_err(|err| err.to_string())?,
+                    topic: row.try_get("topic").map_err(|err| err.to_string())?,
+                    response: serde_json::from_str(&response).map_err(|err| err.to_string())?,
+                })
+            })
+            .collect::<Result<Vec<HandledRequest>, String>>()
+    }
+
+    /// Spawns the write-behind task and returns the handle the actors write through.
+    pub fn spawn_writer(self) -> StorageHandle {
+        let (storage_tx, mut storage_rx) = mpsc::unbounded_channel::<StorageCmd>();
+
+        tokio::spawn(async move {
+            let mut failed: Option<String> = None;
+
+            while let Some(cmd) = storage_rx.recv().await {
+                match cmd {
+                    StorageCmd::Apply(msg) => {
+                        if let Err(err) = self.apply(*msg).await {
+                            eprintln!("[STORAGE] write failed: {}", err);
+                            failed.get_or_insert_with(|| format!("write failed: {}", err));
+                        }
+                    }
+                    StorageCmd::Flush(responder) => {
+                        let flushed = match failed.take() {
+                            Some(err) => Err(err),
+                            None => Ok(()),
+                        };
+                        if responder.send(flushed).is_err() {
+                            eprintln!("[STORAGE] flush responder closed");
+                        }
+                    }
+                }
+            }
+        });
+
+        StorageHandle {
+            storage_tx: Some(storage_tx),
+            journal: JournalHandle::disabled(),
+        }
+    }
+
+    async fn apply(&self, msg: StorageMsg) -> Result<(), sqlx::Error> {
+        match msg {
+            StorageMsg::UpsertUser(user) => {
+                sqlx::query(
+                    "INSERT INTO users (id, email) VALUES ($1, $2)
+                     ON CONFLICT (id) DO UPDATE SET email = EXCLUDED.email",
+                )
+                .bind(user.id)
+                .bind(user.email)
+                .execute(&self.pool)
+                .await?;
+            }
+            StorageMsg::UpsertWallet(wallet) => {
+                let mut tx = self.pool.begin().await?;
+
+                sqlx::query(
+                    "INSERT INTO wallets (user_id) VALUES ($1)
+                     ON CONFLICT (user_id) DO UPDATE SET updated_at = now()",
+                )
+                .bind(&wallet.user_id)
+                .execute(&mut *tx)
+                .await?;
+
+                for (currency, balance) in &wallet.balances {
+                    sqlx::query(
+                        "INSERT INTO wallet_balances (user_id, currency, available, locked)
+                         VALUES ($1, $2, $3, $4)
+                         ON CONFLICT (user_id, currency) DO UPDATE SET
+                            available = EXCLUDED.available,
+                            locked = EXCLUDED.locked",
+                    )
+                    .bind(&wallet.user_id)
+                    .bind(currency)
+                    .bind(balance.available)
+                    .bind(balance.locked)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+
+                tx.commit().await?;
+            }
+            StorageMsg::OpenPosition { user_id, position } => {
+                let trailing_stop = position
+                    .trailing_stop
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()
+                    .map_err(|err| sqlx::Error::Encode(Box::new(err)))?;
+
+                sqlx::query(
+                    "INSERT INTO open_positions
+                        (position_id, user_id, asset, entry_price, qty, margin, stop_loss, take_profit, target_mode,
+                         leverage, liquidation_price, trailing_stop)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                     ON CONFLICT (position_id) DO UPDATE SET
+                        entry_price = EXCLUDED.entry_price,
+                        qty = EXCLUDED.qty,
+                        margin = EXCLUDED.margin,
+                        stop_loss = EXCLUDED.stop_loss,
+                        take_profit = EXCLUDED.take_profit,
+                        target_mode = EXCLUDED.target_mode,
+                        leverage = EXCLUDED.leverage,
+                        liquidation_price = EXCLUDED.liquidation_price,
+                        trailing_stop = EXCLUDED.trailing_stop",
+                )
+                .bind(position.position_id)
+                .bind(user_id)
+                .bind(position.asset)
+                .bind(position.entry_price)
+                .bind(position.qty)
+                .bind(position.margin)
+                .bind(position.stop_loss)
+                .bind(position.take_profit)
+                .bind(position.target_mode.as_str())
+                .bind(position.leverage)
+                .bind(position.liquidation_price)
+                .bind(trailing_stop)
+                .execute(&self.pool)
+                .await?;
+            }
+            StorageMsg::ClosePosition { user_id, closed } => {
+                let mut tx = self.pool.begin().await?;
+
+                sqlx::query("DELETE FROM open_positions WHERE position_id = $1")
+                    .bind(&closed.position.position_id)
+                    .execute(&mut *tx)
+                    .await?;
+
+                sqlx::query(
+                    "INSERT INTO closed_positions
+                        (position_id, user_id, asset, entry_price, exit_price, qty, margin, realized_pnl,
+                         fees)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     ON CONFLICT (position_id) DO NOTHING",
+                )
+                .bind(closed.position.position_id)
+                .bind(user_id)
+                .bind(closed.position.asset)
+                .bind(closed.position.entry_price)
+                .bind(closed.settlement.exit_price)
+                .bind(closed.position.qty)
+                .bind(closed.position.margin)
+                .bind(closed.settlement.realized_pnl)
+                .bind(closed.settlement.fees)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+            }
+            StorageMsg::ReducePosition { user_id, closed } => {
+                let remaining = match closed.remaining {
+                    Some(remaining) => remaining,
+                    None => return Ok(()),
+                };
+                let mut tx = self.pool.begin().await?;
+
+                sqlx::query(
+                    "UPDATE open_positions SET qty = $2, margin = $3, liquidation_price = $4
+                     WHERE position_id = $1",
+                )
+                .bind(&remaining.position_id)
+                .bind(remaining.qty)
+                .bind(remaining.margin)
+                .bind(remaining.liquidation_price)
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query(
+                    "INSERT INTO partial_closes
+                        (position_id, user_id, asset, entry_price, exit_price, qty, margin, realized_pnl,
+                         fees)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                )
+                .bind(remaining.position_id)
+                .bind(user_id)
+                .bind(remaining.asset)
+                .bind(closed.settlement.entry_price)
+                .bind(closed.settlement.exit_price)
+                .bind(closed.settlement.qty)
+                .bind(closed.settlement.returned_margin)
+                .bind(closed.settlement.realized_pnl)
+                .bind(closed.settlement.fees)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+            }
+            StorageMsg::PlaceOrder(pending) => {
+                // the request is only ever read back whole, so it's kept as json
+                let request = serde_json::to_string(&pending.order)
+                    .map_err(|err| sqlx::Error::Encode(Box::new(err)))?;
+
+                sqlx::query(
+                    "INSERT INTO resting_orders (order_id, user_id, asset, request, reserved, placed_at)
+                     VALUES ($1, $2, $3, $4, $5, $6)
+                     ON CONFLICT (order_id) DO NOTHING",
+                )
+                .bind(pending.order.order_id)
+                .bind(pending.user_id)
+                .bind(pending.order.asset)
+                .bind(request)
+                .bind(pending.reserved)
+                .bind(pending.placed_at)
+                .execute(&self.pool)
+                .await?;
+            }
+            StorageMsg::RemoveOrder { order_id } => {
+                sqlx::query("DELETE FROM resting_orders WHERE order_id = $1")
+                    .bind(order_id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            StorageMsg::SetMarginMode { user_id, mode } => {
+                sqlx::query(
+                    "INSERT INTO margin_modes (user_id, mode) VALUES ($1, $2)
+                     ON CONFLICT (user_id) DO UPDATE SET mode = EXCLUDED.mode, updated_at = now()",
+                )
+                .bind(user_id)
+                .bind(mode.as_str())
+                .execute(&self.pool)
+                .await?;
+            }
+            StorageMsg::SetPositionMode { user_id, mode } => {
+                sqlx::query(
+                    "INSERT INTO position_modes (user_id, mode) VALUES ($1, $2)
+                     ON CONFLICT (user_id) DO UPDATE SET mode = EXCLUDED.mode, updated_at = now()",
+                )
+                .bind(user_id)
+                .bind(mode.as_str())
+                .execute(&self.pool)
+                .await?;
+            }
+            StorageMsg::NetOrder(netted) => {
+                let position = serde_json::to_string(&netted.position)
+                    .map_err(|err| sqlx::Error::Encode(Box::new(err)))?;
+
+                sqlx::query(
+                    "INSERT INTO netted_orders (order_id, user_id, position) VALUES ($1, $2, $3)
+                     ON CONFLICT (order_id) DO NOTHING",
+                )
+                .bind(netted.order_id)
+                .bind(netted.user_id)
+                .bind(position)
+                .execute(&self.pool)
+                .await?;
+            }
+            StorageMsg::HandleRequest(handled) => {
+                sqlx::query(
+                    "INSERT INTO handled_requests (request_id, topic, response) VALUES ($1, $2, $3)
+                     ON CONFLICT (request_id) DO NOTHING",
+                )
+                .bind(handled.request_id)
+                .bind(handled.topic)
+                .bind(handled.response.to_string())
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
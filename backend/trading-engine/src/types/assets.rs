@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::types::types::OpenOrderRequest;
+
+#[derive(Clone, Debug)]
+pub struct Instrument {
+    pub symbol: String,
+    pub tick_size: Decimal,
+    pub lot_size: Decimal,
+    pub min_qty: Decimal,
+    pub max_qty: Decimal,
+    // keeps price * qty far from overflowing on orders priced away from the market
+    pub max_price: Decimal,
+    pub max_leverage: Decimal,
+    // share of notional a position must keep as equity before it is liquidated
+    pub maintenance_margin_ratio: Decimal,
+    // charged on the exit notional when a position is closed
+    pub taker_fee_rate: Decimal,
+}
+
+impl Instrument {
+    pub fn check_price(&self, price: Decimal) -> Result<(), String> {
+        if price <= dec!(0) {
+            return Err(format!("Price for {} must be positive", self.symbol));
+        }
+
+        if price > self.max_price {
+            return Err(format!(
+                "Price for {} must be at most {}, got {}",
+                self.symbol, self.max_price, price
+            ));
+        }
+
+        if price % self.tick_size != dec!(0) {
+            return Err(format!(
+                "Price {} is not a multiple of {} tick size {}",
+                price, self.symbol, self.tick_size
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_qty(&self, qty: Decimal) -> Result<(), String> {
+        let size = qty.abs();
+
+        if size < self.min_qty || size > self.max_qty {
+            return Err(format!(
+                "Quantity for {} must be between {} and {}, got {}",
+                self.symbol, self.min_qty, self.max_qty, size
+            ));
+        }
+
+        if size % self.lot_size != dec!(0) {
+            return Err(format!(
+                "Quantity {} is not a multiple of {} lot size {}",
+                size, self.symbol, self.lot_size
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_leverage(&self, leverage: Decimal) -> Result<(), String> {
+        if leverage < dec!(1) || leverage > self.max_leverage {
+            return Err(format!(
+                "Leverage for {} must be between 1 and {}, got {}",
+                self.symbol, self.max_leverage, leverage
+            ));
+        }
+
+        // a position that starts at or below maintenance would be liquidated on the next tick
+        if dec!(1) / leverage <= self.maintenance_margin_ratio {
+            return Err(format!(
+                "Leverage {} leaves {} below its maintenance margin of {}",
+                leverage, self.symbol, self.maintenance_margin_ratio
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct AssetRegistry {
+    instruments: HashMap<String, Instrument>,
+}
+
+impl AssetRegistry {
+    pub fn new(instruments: Vec<Instrument>) -> AssetRegistry {
+        AssetRegistry {
+            instruments: instruments
+                .into_iter()
+                .map(|instrument| (instrument.symbol.clone(), instrument))
+                .collect(),
+        }
+    }
+
+    /// The instruments the price poller currently streams quotes for.
+    pub fn with_defaults() -> AssetRegistry {
+        AssetRegistry::new(vec![
+            Instrument {
+                symbol: "BTC".to_string(),
+                tick_size: dec!(0.1),
+                lot_size: dec!(0.0001),
+                min_qty: dec!(0.0001),
+                max_qty: dec!(100),
+                max_price: dec!(10_000_000),
+                max_leverage: dec!(100),
+                maintenance_margin_ratio: dec!(0.005),
+                taker_fee_rate: dec!(0.0005),
+            },
+            Instrument {
+                symbol: "ETH".to_string(),
+                tick_size: dec!(0.01),
+                lot_size: dec!(0.001),
+                min_qty: dec!(0.001),
+                max_qty: dec!(1_000),
+                max_price: dec!(1_000_000),
+                max_leverage: dec!(50),
+                maintenance_margin_ratio: dec!(0.01),
+                taker_fee_rate: dec!(0.0005),
+            },
+            Instrument {
+                symbol: "SOL".to_string(),
+                tick_size: dec!(0.001),
+                lot_size: dec!(0.01),
+                min_qty: dec!(0.01),
+                max_qty: dec!(10_000),
+                max_price: dec!(100_000),
+                max_leverage: dec!(20),
+                maintenance_margin_ratio: dec!(0.02),
+                taker_fee_rate: dec!(0.0007),
+            },
+        ])
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&Instrument> {
+        self.instruments.get(&symbol.to_uppercase())
+    }
+
+    /// Looks up the order's instrument and checks qty and leverage against it.
+    pub fn validate(&self, order: &OpenOrderRequest) -> Result<&Instrument, String> {
+        let instrument = self
+            .get(&order.asset)
+            .ok_or_else(|| format!("Unknown asset {}", order.asset))?;
+
+        instrument.check_qty(order.qty)?;
+
+        if let Some(leverage) = order.leverage {
+            instrument.check_leverage(leverage)?;
+        }
+
+        Ok(instrument)
+    }
+}
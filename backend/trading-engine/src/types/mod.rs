@@ -1,4 +1,8 @@
+pub mod assets;
+pub mod orders;
 pub mod positions;
+pub mod prices;
+#[allow(clippy::module_inception)]
 pub mod types;
 pub mod users;
 pub mod wallet;
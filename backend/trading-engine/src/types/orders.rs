@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{
+    positions::Position,
+    prices::Quote,
+    types::{OpenOrderRequest, OrderType},
+};
+
+// how many of the most recent order ids are remembered for deduplication
+const ORDER_DEDUP_WINDOW: usize = 10_000;
+
+/// What handling an order did, returned to the caller and kept for retries.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "status", content = "position", rename_all = "snake_case")]
+pub enum OrderStatus {
+    Filled(Box<Position>),
+    // waiting in the book with its margin held
+    Resting,
+    // an IOC or FOK limit order that could not fill on arrival
+    Expired,
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Filled(_) => "filled",
+            OrderStatus::Resting => "resting",
+            OrderStatus::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SeenOrder {
+    request: OpenOrderRequest,
+    result: Result<OrderStatus, String>,
+}
+
+/// Recently seen order ids and what opening them returned, so a retried order gets
+/// the original answer instead of being executed twice.
+#[derive(Debug)]
+pub struct RecentOrders {
+    capacity: usize,
+    // oldest first, evicted once the window is full
+    order_ids: VecDeque<String>,
+    seen: HashMap<String, SeenOrder>,
+}
+
+impl RecentOrders {
+    pub fn new() -> RecentOrders {
+        RecentOrders::with_capacity(ORDER_DEDUP_WINDOW)
+    }
+
+    pub fn with_capacity(capacity: usize) -> RecentOrders {
+        RecentOrders {
+            capacity,
+            order_ids: VecDeque::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// The original result if `order` was already handled, or an error if its id was
+    /// used before for a different order. `None` means it has not been seen.
+    pub fn check(&self, order: &OpenOrderRequest) -> Option<Result<OrderStatus, String>> {
+        let seen = self.seen.get(&order.order_id)?;
+
+        if seen.request != *order {
+            return Some(Err(format!(
+                "Order id {} was already used for a different order",
+                order.order_id
+            )));
+        }
+
+        Some(seen.result.clone())
+    }
+
+    pub fn record(&mut self, order: OpenOrderRequest, result: Result<OrderStatus, String>) {
+        if self.seen.contains_key(&order.order_id) {
+            return;
+        }
+
+        if self.order_ids.len() >= self.capacity {
+            if let Some(oldest) = self.order_ids.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.order_ids.push_back(order.order_id.clone());
+        self.seen.insert(
+            order.order_id.clone(),
+            SeenOrder {
+                request: order,
+                result,
+            },
+        );
+    }
+
+    /// Replaces the result of an order still in the window, e.g. once a resting order fills.
+    pub fn update(&mut self, order_id: &str, result: Result<OrderStatus, String>) {
+        if let Some(seen) = self.seen.get_mut(order_id) {
+            seen.result = result;
+        }
+    }
+}
+
+/// A one-way order that netted into a position it didn't open. Nothing else is keyed by its
+/// id, so it's kept to stop a redelivery after a restart from being applied twice.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NettedOrder {
+    pub user_id: String,
+    pub order_id: String,
+    // the net position the order left behind, qty 0 when it closed it
+    pub position: Position,
+}
+
+/// An order waiting in the book, with the margin held back from the wallet for it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingOrder {
+    pub user_id: String,
+    pub order: OpenOrderRequest,
+    pub reserved: Decimal,
+    pub placed_at: DateTime<Utc>,
+}
+
+impl PendingOrder {
+    /// Whether `quote` lets the order fill at its limit price.
+    pub fn crosses(&self, quote: &Quote) -> bool {
+        let price = match self.order.price {
+            Some(price) => price,
+            None => return false,
+        };
+
+        if self.order.qty > Decimal::ZERO {
+            quote.ask <= price
+        } else {
+            quote.bid >= price
+        }
+    }
+
+    /// Whether the mark has reached the stop's trigger, from below for buys and above for sells.
+    pub fn triggered(&self, quote: &Quote) -> bool {
+        let trigger_price = match self.order.trigger_price {
+            Some(trigger_price) => trigger_price,
+            None => return false,
+        };
+
+        if self.order.qty > Decimal::ZERO {
+            quote.ask >= trigger_price
+        } else {
+            quote.bid <= trigger_price
+        }
+    }
+
+    /// Whether the order should leave the book on `quote`, to fill or to trigger.
+    pub fn is_ready(&self, quote: &Quote) -> bool {
+        match self.order.order_type {
+            OrderType::Market => true,
+            OrderType::Limit => self.crosses(quote),
+            OrderType::StopMarket | OrderType::StopLimit => self.triggered(quote),
+        }
+    }
+}
+
+/// Resting orders per asset symbol, oldest first so earlier orders fill first.
+#[derive(Debug, Default)]
+pub struct RestingOrders {
+    by_asset: HashMap<String, Vec<PendingOrder>>,
+}
+
+impl RestingOrders {
+    pub fn new() -> RestingOrders {
+        RestingOrders::default()
+    }
+
+    pub fn add(&mut self, asset: String, pending: PendingOrder) {
+        self.by_asset.entry(asset).or_default().push(pending);
+    }
+
+    pub fn contains(&self, order_id: &str) -> bool {
+        self.by_asset
+            .values()
+            .flatten()
+            .any(|pending| pending.order.order_id == order_id)
+    }
+
+    pub fn get(&self, user_id: &str, order_id: &str) -> Option<&PendingOrder> {
+        self.by_asset
+            .values()
+            .flatten()
+            .find(|pending| pending.user_id == user_id && pending.order.order_id == order_id)
+    }
+
+    pub fn remove(&mut self, user_id: &str, order_id: &str) -> Option<PendingOrder> {
+        for pending_orders in self.by_asset.values_mut() {
+            if let Some(index) = pending_orders.iter().position(|pending| {
+                pending.user_id == user_id && pending.order.order_id == order_id
+            }) {
+                return Some(pending_orders.remove(index));
+            }
+        }
+        None
+    }
+
+    pub fn assets(&self) -> Vec<String> {
+        self.by_asset
+            .iter()
+            .filter(|(_, pending_orders)| !pending_orders.is_empty())
+            .map(|(asset, _)| asset.clone())
+            .collect()
+    }
+
+    /// Removes and returns every order in `asset` that is ready to fill or trigger on `quote`.
+    pub fn take_ready(&mut self, asset: &str, quote: &Quote) -> Vec<PendingOrder> {
+        let pending_orders = match self.by_asset.get_mut(asset) {
+            Some(pending_orders) => pending_orders,
+            None => return Vec::new(),
+        };
+
+        let (crossed, resting) = pending_orders
+            .drain(..)
+            .partition(|pending| pending.is_ready(quote));
+        *pending_orders = resting;
+
+        crossed
+    }
+
+    pub fn snapshot(&self) -> Vec<PendingOrder> {
+        self.by_asset.values().flatten().cloned().collect()
+    }
+}
@@ -1,16 +1,21 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
-use arc_swap::ArcSwapAny;
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc::UnboundedSender, oneshot};
 
-use crate::types::types::{CurrentPrice, OpenOrderRequest, WalletManagerMsg};
+use crate::storage::{StorageHandle, StorageMsg};
+use crate::types::{
+    assets::AssetRegistry,
+    orders::{NettedOrder, OrderStatus, PendingOrder, RecentOrders, RestingOrders},
+    prices::PriceStore,
+    types::{OpenOrderRequest, OrderType, TimeInForce, WalletManagerMsg},
+    wallet::{Wallet, WalletError, COLLATERAL},
+};
 
-const LIQUIDATION_THRESHOLD: Decimal = dec!(0.1);
-
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Position {
     pub position_id: String,
     pub asset: String,
@@ -20,21 +25,592 @@ pub struct Position {
     pub margin: Decimal,
     pub stop_loss: Option<Decimal>,
     pub take_profit: Option<Decimal>,
-    pub leverage: Option<Decimal>,
+    // positions stored before target modes existed had pnl targets
+    #[serde(default = "TargetMode::pnl")]
+    pub target_mode: TargetMode,
+    pub leverage: Decimal,
+    // mark price at which equity falls to the asset's maintenance margin,
+    // only enforced per position in isolated mode
+    pub liquidation_price: Decimal,
+    #[serde(default)]
+    pub trailing_stop: Option<TrailingStop>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailKind {
+    // a fixed price distance behind the best mark
+    Distance,
+    // a percentage of the best mark, e.g. 2 for 2%
+    Percent,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TrailingStopRequest {
+    pub kind: TrailKind,
+    pub value: Decimal,
+}
+
+/// A stop that follows the best mark seen since entry and never moves back.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrailingStop {
+    pub kind: TrailKind,
+    pub value: Decimal,
+    pub best_price: Decimal,
+    pub trigger_price: Decimal,
+}
+
+impl TrailingStop {
+    pub fn new(
+        request: &TrailingStopRequest,
+        entry_price: Decimal,
+        qty: Decimal,
+    ) -> Result<TrailingStop, String> {
+        if request.value <= dec!(0) {
+            return Err("Trailing stop must be positive".to_string());
+        }
+        if request.kind == TrailKind::Percent && request.value >= dec!(100) {
+            return Err("Trailing stop percentage must be below 100".to_string());
+        }
+
+        let mut trailing_stop = TrailingStop {
+            kind: request.kind,
+            value: request.value,
+            best_price: entry_price,
+            trigger_price: dec!(0),
+        };
+        trailing_stop.trigger_price = trailing_stop.trigger_for(entry_price, qty);
+
+        Ok(trailing_stop)
+    }
+
+    fn trigger_for(&self, best_price: Decimal, qty: Decimal) -> Decimal {
+        let offset = match self.kind {
+            TrailKind::Distance => self.value,
+            TrailKind::Percent => best_price * self.value / dec!(100),
+        };
+
+        if qty > dec!(0) {
+            best_price - offset
+        } else {
+            best_price + offset
+        }
+    }
+
+    /// Moves the trigger along if `mark_price` is a new best, returning whether it moved.
+    pub fn ratchet(&mut self, mark_price: Decimal, qty: Decimal) -> bool {
+        let is_better = if qty > dec!(0) {
+            mark_price > self.best_price
+        } else {
+            mark_price < self.best_price
+        };
+
+        if !is_better {
+            return false;
+        }
+
+        self.best_price = mark_price;
+        self.trigger_price = self.trigger_for(mark_price, qty);
+        true
+    }
+
+    pub fn is_hit(&self, mark_price: Decimal, qty: Decimal) -> bool {
+        if qty > dec!(0) {
+            mark_price <= self.trigger_price
+        } else {
+            mark_price >= self.trigger_price
+        }
+    }
+}
+
+impl Position {
+    pub fn notional(&self, price: Decimal) -> Decimal {
+        price * self.qty.abs()
+    }
+
+    /// Solves `margin + (p - entry) * qty = maintenance_margin_ratio * p * |qty|` for `p`.
+    /// Zero for a long that can't be liquidated because its margin covers the whole notional.
+    pub fn compute_liquidation_price(&self, maintenance_margin_ratio: Decimal) -> Decimal {
+        let side = if self.qty > dec!(0) {
+            dec!(1)
+        } else {
+            dec!(-1)
+        };
+        let price = (self.entry_price * self.qty - self.margin)
+            / (self.qty * (dec!(1) - maintenance_margin_ratio * side));
+
+        price.max(dec!(0))
+    }
+
+    /// Settles `qty` of the position (same sign as `self.qty`) at `exit_price`, releasing
+    /// the same share of its margin. An isolated position can lose at most the margin it
+    /// releases; in cross mode a negative amount is taken from the wallet.
+    pub fn settle(
+        &self,
+        qty: Decimal,
+        exit_price: Decimal,
+        taker_fee_rate: Decimal,
+        margin_mode: MarginMode,
+    ) -> Settlement {
+        let returned_margin = if qty == self.qty {
+            self.margin
+        } else {
+            self.margin * qty / self.qty
+        };
+        let realized_pnl = (exit_price - self.entry_price) * qty;
+        let fees = exit_price * qty.abs() * taker_fee_rate;
+        let owed = returned_margin + realized_pnl - fees;
+
+        let amount = match margin_mode {
+            MarginMode::Isolated => owed.max(dec!(0)),
+            MarginMode::Cross => owed,
+        };
+
+        let settlement = Settlement {
+            entry_price: self.entry_price,
+            exit_price,
+            qty,
+            fees,
+            realized_pnl,
+            returned_margin,
+            amount,
+        };
+
+        debug_assert!(qty.abs() <= self.qty.abs() && qty * self.qty > dec!(0));
+        debug_assert!(settlement.returned_margin <= self.margin);
+        debug_assert!(settlement.fees >= dec!(0));
+        debug_assert!(margin_mode == MarginMode::Cross || settlement.amount >= dec!(0));
+
+        settlement
+    }
+
+    pub fn is_liquidatable(&self, mark_price: Decimal) -> bool {
+        if self.qty > dec!(0) {
+            mark_price <= self.liquidation_price
+        } else {
+            mark_price >= self.liquidation_price
+        }
+    }
+
+    /// Expects `pnl` to already be marked at `mark_price`.
+    pub fn is_stop_loss_hit(&self, mark_price: Decimal) -> bool {
+        self.stop_loss
+            .is_some_and(|stop_loss| match self.target_mode {
+                TargetMode::Price if self.qty > dec!(0) => mark_price <= stop_loss,
+                TargetMode::Price => mark_price >= stop_loss,
+                TargetMode::Pnl => self.pnl <= stop_loss,
+            })
+    }
+
+    /// Expects `pnl` to already be marked at `mark_price`.
+    pub fn is_take_profit_hit(&self, mark_price: Decimal) -> bool {
+        self.take_profit
+            .is_some_and(|take_profit| match self.target_mode {
+                TargetMode::Price if self.qty > dec!(0) => mark_price >= take_profit,
+                TargetMode::Price => mark_price <= take_profit,
+                TargetMode::Pnl => self.pnl >= take_profit,
+            })
+    }
+}
+
+/// What `stop_loss` and `take_profit` are compared against.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetMode {
+    // the mark price the position closes against
+    #[default]
+    Price,
+    // the position's unrealized pnl
+    Pnl,
+}
+
+impl TargetMode {
+    fn pnl() -> TargetMode {
+        TargetMode::Pnl
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TargetMode::Price => "price",
+            TargetMode::Pnl => "pnl",
+        }
+    }
+
+    /// Checks targets for a position of `qty` entered or marked at `price`: price targets
+    /// must sit on the losing and winning side of it, pnl targets below and above zero.
+    pub fn check_targets(
+        &self,
+        stop_loss: Option<Decimal>,
+        take_profit: Option<Decimal>,
+        qty: Decimal,
+        price: Decimal,
+    ) -> Result<(), String> {
+        let is_long = qty > dec!(0);
+
+        match self {
+            TargetMode::Price => {
+                if let Some(stop_loss) = stop_loss {
+                    if stop_loss <= dec!(0) {
+                        return Err("Stop-loss price must be positive".to_string());
+                    }
+                    if is_long && stop_loss >= price {
+                        return Err(format!(
+                            "Stop-loss {} must be below {} for a long",
+                            stop_loss, price
+                        ));
+                    }
+                    if !is_long && stop_loss <= price {
+                        return Err(format!(
+                            "Stop-loss {} must be above {} for a short",
+                            stop_loss, price
+                        ));
+                    }
+                }
+                if let Some(take_profit) = take_profit {
+                    if take_profit <= dec!(0) {
+                        return Err("Take-profit price must be positive".to_string());
+                    }
+                    if is_long && take_profit <= price {
+                        return Err(format!(
+                            "Take-profit {} must be above {} for a long",
+                            take_profit, price
+                        ));
+                    }
+                    if !is_long && take_profit >= price {
+                        return Err(format!(
+                            "Take-profit {} must be below {} for a short",
+                            take_profit, price
+                        ));
+                    }
+                }
+            }
+            TargetMode::Pnl => {
+                if stop_loss.is_some_and(|stop_loss| stop_loss >= dec!(0)) {
+                    return Err("Stop-loss pnl must be negative".to_string());
+                }
+                if take_profit.is_some_and(|take_profit| take_profit <= dec!(0)) {
+                    return Err("Take-profit pnl must be positive".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for TargetMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<TargetMode, String> {
+        match mode {
+            "price" => Ok(TargetMode::Price),
+            "pnl" => Ok(TargetMode::Pnl),
+            _ => Err(format!("Unknown target mode {}", mode)),
+        }
+    }
+}
+
+/// What closing a position paid back to the wallet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Settlement {
+    pub entry_price: Decimal,
+    pub exit_price: Decimal,
+    pub qty: Decimal,
+    pub fees: Decimal,
+    // before fees
+    pub realized_pnl: Decimal,
+    pub returned_margin: Decimal,
+    // credited to the wallet, or debited when negative
+    pub amount: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClosedPosition {
+    // the closed portion, with the qty and margin that were settled
+    pub position: Position,
+    pub settlement: Settlement,
+    // what is left open after a partial close
+    #[serde(default)]
+    pub remaining: Option<Position>,
+}
+
+/// How a user's positions are collateralized. Isolated positions are each backed by their
+/// own margin only; in cross mode the wallet balance plus unrealized PnL across all of the
+/// user's positions backs their combined maintenance margin.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MarginMode {
+    #[default]
+    Isolated,
+    Cross,
+}
+
+impl MarginMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MarginMode::Isolated => "isolated",
+            MarginMode::Cross => "cross",
+        }
+    }
+}
+
+impl FromStr for MarginMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<MarginMode, String> {
+        match mode {
+            "isolated" => Ok(MarginMode::Isolated),
+            "cross" => Ok(MarginMode::Cross),
+            _ => Err(format!("Unknown margin mode {}", mode)),
+        }
+    }
+}
+
+/// Hedge mode keeps every order as its own position. In one-way mode a user holds at most
+/// one position per asset and new orders increase, reduce or flip it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionMode {
+    #[default]
+    Hedge,
+    OneWay,
+}
+
+impl PositionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PositionMode::Hedge => "hedge",
+            PositionMode::OneWay => "one_way",
+        }
+    }
+}
+
+impl FromStr for PositionMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<PositionMode, String> {
+        match mode {
+            "hedge" => Ok(PositionMode::Hedge),
+            "one_way" => Ok(PositionMode::OneWay),
+            _ => Err(format!("Unknown position mode {}", mode)),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    Liquidation,
+    StopLoss,
+    TrailingStop,
+    TakeProfit,
+}
+
+/// What `plan_open` worked out for an order that is about to open a new position.
+struct OpenPlan {
+    asset: String,
+    entry_price: Decimal,
+    leverage: Decimal,
+    // initial margin plus any extra margin the order posts
+    amount_required: Decimal,
+    maintenance_margin_ratio: Decimal,
+    trailing_stop: Option<TrailingStop>,
+}
+
+/// Amendments to an open position, anything left out stays as it is.
+#[derive(Clone, Debug, Default)]
+pub struct PositionChanges {
+    // defaults to the position's current mode, switching it needs both targets given or cleared
+    pub target_mode: Option<TargetMode>,
+    pub stop_loss: Option<Decimal>,
+    pub take_profit: Option<Decimal>,
+    pub clear_stop_loss: bool,
+    pub clear_take_profit: bool,
+    // isolated only, added from the wallet when positive and returned to it when negative
+    pub margin_delta: Option<Decimal>,
+}
+
+/// Emitted by a risk pass for every position it force-closed.
+#[derive(Serialize, Clone, Debug)]
+pub struct RiskEvent {
+    pub user_id: String,
+    pub position_id: String,
+    pub asset: String,
+    pub reason: CloseReason,
+    pub qty: Decimal,
+    pub entry_price: Decimal,
+    pub exit_price: Decimal,
+    pub realized_pnl: Decimal,
+    pub fees: Decimal,
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Debug)]
 pub struct Positions {
     pub position_map: HashMap<String, Vec<Position>>,
-    pub latest_price: Arc<ArcSwapAny<Arc<CurrentPrice>>>,
+    pub prices: Arc<PriceStore>,
+    pub assets: AssetRegistry,
+    margin_modes: HashMap<String, MarginMode>,
+    position_modes: HashMap<String, PositionMode>,
+    recent_orders: RecentOrders,
+    resting: RestingOrders,
+    // by order id
+    netted_orders: HashMap<String, NettedOrder>,
+    storage: StorageHandle,
 }
 
 impl Positions {
-    pub fn new(latest_price: Arc<ArcSwapAny<Arc<CurrentPrice>>>) -> Positions {
-        return Positions {
+    pub fn new(
+        prices: Arc<PriceStore>,
+        assets: AssetRegistry,
+        storage: StorageHandle,
+    ) -> Positions {
+        Positions {
             position_map: HashMap::new(),
-            latest_price,
-        };
+            prices,
+            assets,
+            margin_modes: HashMap::new(),
+            position_modes: HashMap::new(),
+            recent_orders: RecentOrders::new(),
+            resting: RestingOrders::new(),
+            netted_orders: HashMap::new(),
+            storage,
+        }
+    }
+
+    pub fn hydrate(&mut self, positions: Vec<(String, Position)>) {
+        for (user_id, mut position) in positions {
+            // recomputed so rows written before liquidation prices were stored get one
+            if let Some(instrument) = self.assets.get(&position.asset) {
+                position.liquidation_price =
+                    position.compute_liquidation_price(instrument.maintenance_margin_ratio);
+            }
+            self.position_map.entry(user_id).or_default().push(position);
+        }
+    }
+
+    pub fn hydrate_margin_modes(&mut self, margin_modes: Vec<(String, MarginMode)>) {
+        self.margin_modes.extend(margin_modes);
+    }
+
+    pub fn margin_mode(&self, user_id: &String) -> MarginMode {
+        self.margin_modes.get(user_id).copied().unwrap_or_default()
+    }
+
+    pub fn margin_modes_snapshot(&self) -> Vec<(String, MarginMode)> {
+        self.margin_modes
+            .iter()
+            .map(|(user_id, mode)| (user_id.clone(), *mode))
+            .collect()
+    }
+
+    /// Switching modes changes what backs open positions, so it's only allowed with none open.
+    pub fn set_margin_mode(
+        &mut self,
+        user_id: String,
+        mode: MarginMode,
+    ) -> Result<MarginMode, String> {
+        if self.margin_mode(&user_id) == mode {
+            return Ok(mode);
+        }
+
+        if self
+            .position_map
+            .get(&user_id)
+            .is_some_and(|positions| !positions.is_empty())
+        {
+            return Err("Close all positions before changing margin mode".to_string());
+        }
+
+        self.storage.send(StorageMsg::SetMarginMode {
+            user_id: user_id.clone(),
+            mode,
+        });
+        self.margin_modes.insert(user_id, mode);
+
+        Ok(mode)
+    }
+
+    pub fn hydrate_position_modes(&mut self, position_modes: Vec<(String, PositionMode)>) {
+        self.position_modes.extend(position_modes);
+    }
+
+    pub fn position_mode(&self, user_id: &String) -> PositionMode {
+        self.position_modes
+            .get(user_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn position_modes_snapshot(&self) -> Vec<(String, PositionMode)> {
+        self.position_modes
+            .iter()
+            .map(|(user_id, mode)| (user_id.clone(), *mode))
+            .collect()
+    }
+
+    /// Like margin mode, hedge positions can't be merged after the fact so this needs none open.
+    pub fn set_position_mode(
+        &mut self,
+        user_id: String,
+        mode: PositionMode,
+    ) -> Result<PositionMode, String> {
+        if self.position_mode(&user_id) == mode {
+            return Ok(mode);
+        }
+
+        if self
+            .position_map
+            .get(&user_id)
+            .is_some_and(|positions| !positions.is_empty())
+        {
+            return Err("Close all positions before changing position mode".to_string());
+        }
+
+        self.storage.send(StorageMsg::SetPositionMode {
+            user_id: user_id.clone(),
+            mode,
+        });
+        self.position_modes.insert(user_id, mode);
+
+        Ok(mode)
+    }
+
+    pub fn hydrate_resting_orders(&mut self, resting_orders: Vec<PendingOrder>) {
+        for pending in resting_orders {
+            match self.assets.get(&pending.order.asset) {
+                Some(instrument) => self.resting.add(instrument.symbol.clone(), pending),
+                None => eprintln!(
+                    "[POSITIONS] dropping resting order {} in unknown asset {}",
+                    pending.order.order_id, pending.order.asset
+                ),
+            }
+        }
+    }
+
+    pub fn hydrate_netted_orders(&mut self, netted_orders: Vec<NettedOrder>) {
+        for netted in netted_orders {
+            self.netted_orders.insert(netted.order_id.clone(), netted);
+        }
+    }
+
+    pub fn netted_orders_snapshot(&self) -> Vec<NettedOrder> {
+        self.netted_orders.values().cloned().collect()
+    }
+
+    pub fn resting_orders_snapshot(&self) -> Vec<PendingOrder> {
+        self.resting.snapshot()
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, Position)> {
+        self.position_map
+            .iter()
+            .flat_map(|(user_id, positions)| {
+                positions
+                    .iter()
+                    .map(move |position| (user_id.clone(), position.clone()))
+            })
+            .collect()
     }
 
     pub async fn open(
@@ -42,155 +618,835 @@ impl Positions {
         user_id: String,
         order: OpenOrderRequest,
         wallet_tx: UnboundedSender<WalletManagerMsg>,
-    ) -> Result<String, String> {
-        let (responder_tx, responder_rx) = oneshot::channel::<Option<Decimal>>();
-        let sent = wallet_tx.send(WalletManagerMsg::GetBalance {
-            user_id: user_id.clone(),
-            responder: responder_tx,
-        });
+    ) -> Result<OrderStatus, String> {
+        if let Some(result) = self.recent_orders.check(&order) {
+            return result;
+        }
 
-        if let Err(_) = sent {
-            eprintln!("[error responding to open position function call]");
+        if self.resting.contains(&order.order_id) {
+            return Ok(OrderStatus::Resting);
         }
 
-        let balance: Decimal = match responder_rx.await {
-            Ok(Some(balance)) => balance,
-            Ok(None) => return Err("Wallet not found".to_string()),
-            Err(err) => return Err(err.to_string()),
+        // the window is in memory only, after a restart the open position itself
+        // keeps a redelivered order from opening twice or debiting the wallet again
+        for (owner_id, positions) in self.position_map.iter() {
+            if let Some(existing) = positions.iter().find(|p| p.position_id == order.order_id) {
+                if *owner_id != user_id {
+                    return Err(format!(
+                        "Order id {} was already used for a different order",
+                        order.order_id
+                    ));
+                }
+                return Ok(OrderStatus::Filled(Box::new(existing.clone())));
+            }
+        }
+        if let Some(netted) = self.netted_orders.get(&order.order_id) {
+            if netted.user_id != user_id {
+                return Err(format!(
+                    "Order id {} was already used for a different order",
+                    order.order_id
+                ));
+            }
+            return Ok(OrderStatus::Filled(Box::new(netted.position.clone())));
+        }
+
+        let result = match order.order_type {
+            OrderType::Market => self
+                .execute(user_id, order.clone(), None, dec!(0), wallet_tx)
+                .await
+                .map(|position| OrderStatus::Filled(Box::new(position))),
+            OrderType::Limit => self.place_limit(user_id, order.clone(), wallet_tx).await,
+            OrderType::StopMarket | OrderType::StopLimit => {
+                self.place_stop(user_id, order.clone(), wallet_tx).await
+            }
         };
+        self.recent_orders.record(order, result.clone());
 
-        let current_price = if order.qty < dec!(0) {
-            self.latest_price.load().bid
-        } else {
-            self.latest_price.load().ask
+        result
+    }
+
+    /// Fills `order` now, at the live quote or at `fill_price` when given. A resting order
+    /// pays for the fill out of its `reserved` hold, which is left untouched on error.
+    async fn execute(
+        &mut self,
+        user_id: String,
+        order: OpenOrderRequest,
+        fill_price: Option<Decimal>,
+        reserved: Decimal,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<Position, String> {
+        let net_position_id = match self.position_mode(&user_id) {
+            PositionMode::OneWay => self.position_map.get(&user_id).and_then(|positions| {
+                positions
+                    .iter()
+                    .find(|p| p.asset.eq_ignore_ascii_case(&order.asset))
+                    .map(|p| p.position_id.clone())
+            }),
+            PositionMode::Hedge => None,
         };
 
-        if current_price == dec!(0) {
-            return Err("Could not process order, server error".to_string());
+        match net_position_id {
+            Some(position_id) => {
+                let order_id = order.order_id.clone();
+                let position = self
+                    .net_order(
+                        user_id.clone(),
+                        position_id,
+                        order,
+                        fill_price,
+                        reserved,
+                        wallet_tx,
+                    )
+                    .await?;
+
+                let netted = NettedOrder {
+                    user_id,
+                    order_id: order_id.clone(),
+                    position: position.clone(),
+                };
+                self.storage.send(StorageMsg::NetOrder(netted.clone()));
+                self.netted_orders.insert(order_id, netted);
+
+                Ok(position)
+            }
+            None => {
+                self.open_order(user_id, order, fill_price, reserved, wallet_tx)
+                    .await
+            }
         }
+    }
 
-        let entry_price = if order.qty > dec!(0) {
-            self.latest_price.load().ask
-        } else {
-            self.latest_price.load().bid
+    /// Fills a limit order that crosses on arrival, otherwise rests it with its margin
+    /// held out of the wallet, or expires it if it may not rest.
+    async fn place_limit(
+        &mut self,
+        user_id: String,
+        order: OpenOrderRequest,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<OrderStatus, String> {
+        let instrument = self.assets.validate(&order)?;
+        let asset = instrument.symbol.clone();
+        let price = order
+            .price
+            .ok_or_else(|| "Limit orders need a price".to_string())?;
+        instrument.check_price(price)?;
+        // checked again against the actual fill
+        order
+            .target_mode
+            .check_targets(order.stop_loss, order.take_profit, order.qty, price)?;
+
+        let quote = self.prices.live(&asset)?;
+        let pending = PendingOrder {
+            user_id,
+            order,
+            reserved: dec!(0),
+            placed_at: Utc::now(),
+        };
+
+        // a marketable limit order takes the quote, which is at or better than its price
+        if pending.crosses(&quote) {
+            return self
+                .execute(pending.user_id, pending.order, None, dec!(0), wallet_tx)
+                .await
+                .map(|position| OrderStatus::Filled(Box::new(position)));
+        }
+
+        if pending.order.time_in_force != TimeInForce::Gtc {
+            return Ok(OrderStatus::Expired);
+        }
+
+        self.rest(asset, pending, price, wallet_tx).await
+    }
+
+    /// Rests a stop order until the mark crosses its trigger price. Stops that would
+    /// trigger straight away are rejected rather than filled.
+    async fn place_stop(
+        &mut self,
+        user_id: String,
+        order: OpenOrderRequest,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<OrderStatus, String> {
+        let instrument = self.assets.validate(&order)?;
+        let asset = instrument.symbol.clone();
+        let trigger_price = order
+            .trigger_price
+            .ok_or_else(|| "Stop orders need a trigger price".to_string())?;
+        instrument.check_price(trigger_price)?;
+
+        // margin is held at the price the order is expected to fill at
+        let reserve_price = match order.order_type {
+            OrderType::StopLimit => {
+                let price = order
+                    .price
+                    .ok_or_else(|| "Stop-limit orders need a price".to_string())?;
+                instrument.check_price(price)?;
+                price
+            }
+            _ => trigger_price,
         };
+        order.target_mode.check_targets(
+            order.stop_loss,
+            order.take_profit,
+            order.qty,
+            reserve_price,
+        )?;
 
-        let margin = order.margin.unwrap_or(dec!(0));
+        let quote = self.prices.live(&asset)?;
+        let pending = PendingOrder {
+            user_id,
+            order,
+            reserved: dec!(0),
+            placed_at: Utc::now(),
+        };
 
-        if margin < dec!(0) {
+        if pending.is_ready(&quote) {
+            return Err(format!(
+                "Trigger price {} would trigger immediately, market is {} / {}",
+                trigger_price, quote.bid, quote.ask
+            ));
+        }
+
+        self.rest(asset, pending, reserve_price, wallet_tx).await
+    }
+
+    /// Holds margin for `pending` at `reserve_price` out of the wallet and adds it to the book.
+    async fn rest(
+        &mut self,
+        asset: String,
+        pending: PendingOrder,
+        reserve_price: Decimal,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<OrderStatus, String> {
+        let extra_margin = pending.order.margin.unwrap_or(dec!(0));
+        if extra_margin < dec!(0) {
             return Err("Margin cannot be negative".to_string());
         }
+        let leverage = pending.order.leverage.unwrap_or(dec!(1));
+        let reserved = (reserve_price * pending.order.qty.abs() / leverage)
+            .checked_add(extra_margin)
+            .ok_or_else(|| format!("Margin {} is too large", extra_margin))?;
+
+        reserve(&wallet_tx, &pending.user_id, reserved).await?;
+
+        let pending = PendingOrder {
+            reserved,
+            ..pending
+        };
+        self.storage.send(StorageMsg::PlaceOrder(pending.clone()));
+        self.resting.add(asset, pending);
+
+        Ok(OrderStatus::Resting)
+    }
+
+    /// Pulls a resting order and hands its reserved margin back to the wallet.
+    pub async fn cancel(
+        &mut self,
+        user_id: &str,
+        order_id: &str,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<PendingOrder, String> {
+        let reserved = self
+            .resting
+            .get(user_id, order_id)
+            .map(|pending| pending.reserved)
+            .ok_or_else(|| "Could not find resting order".to_string())?;
+
+        release(&wallet_tx, user_id, reserved).await?;
+
+        let pending = self
+            .resting
+            .remove(user_id, order_id)
+            .ok_or_else(|| "Could not find resting order".to_string())?;
+        self.storage.send(StorageMsg::RemoveOrder {
+            order_id: order_id.to_string(),
+        });
+        self.recent_orders
+            .update(order_id, Err("Order was cancelled".to_string()));
+
+        Ok(pending)
+    }
+
+    /// Fills every resting limit order the latest quotes cross, at its limit price, and
+    /// triggers stop orders the mark has moved through: stop-markets fill at the quote and
+    /// stop-limits become limit orders.
+    pub async fn fill_resting(&mut self, wallet_tx: UnboundedSender<WalletManagerMsg>) {
+        for asset in self.resting.assets() {
+            let quote = match self.prices.live(&asset) {
+                Ok(quote) => quote,
+                Err(_) => continue,
+            };
+
+            for pending in self.resting.take_ready(&asset, &quote) {
+                let order_id = pending.order.order_id.clone();
+                self.storage.send(StorageMsg::RemoveOrder {
+                    order_id: order_id.clone(),
+                });
+
+                let result = match pending.order.order_type {
+                    // the stop's hold goes back, the limit order it becomes takes its own
+                    OrderType::StopLimit => {
+                        match release(&wallet_tx, &pending.user_id, pending.reserved).await {
+                            Ok(()) => {
+                                let order = OpenOrderRequest {
+                                    order_type: OrderType::Limit,
+                                    ..pending.order
+                                };
+                                self.place_limit(pending.user_id, order, wallet_tx.clone())
+                                    .await
+                            }
+                            Err(err) => Err(err),
+                        }
+                    }
+                    OrderType::StopMarket | OrderType::Limit | OrderType::Market => {
+                        let fill_price = match pending.order.order_type {
+                            OrderType::StopMarket => None,
+                            _ => pending.order.price,
+                        };
+                        let filled = self
+                            .execute(
+                                pending.user_id.clone(),
+                                pending.order,
+                                fill_price,
+                                pending.reserved,
+                                wallet_tx.clone(),
+                            )
+                            .await;
+
+                        if filled.is_err() {
+                            if let Err(err) =
+                                release(&wallet_tx, &pending.user_id, pending.reserved).await
+                            {
+                                eprintln!("[FILL RESTING] could not release {}: {}", order_id, err);
+                            }
+                        }
+
+                        filled.map(|position| OrderStatus::Filled(Box::new(position)))
+                    }
+                };
+
+                if let Err(err) = &result {
+                    eprintln!("[FILL RESTING] could not fill {}: {}", order_id, err);
+                }
+                self.recent_orders.update(&order_id, result);
+            }
+        }
+    }
+
+    /// Applies a one-way order to the user's existing position in the asset and returns the
+    /// resulting net position, which is flat (qty 0) when the order closed it exactly.
+    async fn net_order(
+        &mut self,
+        user_id: String,
+        position_id: String,
+        order: OpenOrderRequest,
+        fill_price: Option<Decimal>,
+        reserved: Decimal,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<Position, String> {
+        let instrument = self.assets.validate(&order)?;
+        let maintenance_margin_ratio = instrument.maintenance_margin_ratio;
+
+        let existing = self
+            .position_map
+            .get(&user_id)
+            .and_then(|positions| positions.iter().find(|p| p.position_id == position_id))
+            .cloned()
+            .ok_or_else(|| "Could not find position".to_string())?;
 
-        let amount_required = current_price * order.qty.abs() + margin;
+        // reduce or flip, settled like a close at the side of the book the order fills on
+        if existing.qty * order.qty < dec!(0) {
+            if order.qty.abs() <= existing.qty.abs() {
+                let closed = self
+                    .close(
+                        &user_id,
+                        position_id,
+                        Some(order.qty.abs()),
+                        wallet_tx.clone(),
+                    )
+                    .await?;
+                // reducing takes no margin, so a resting order's hold goes back untouched
+                if reserved > dec!(0) {
+                    release(&wallet_tx, &user_id, reserved).await?;
+                }
+
+                return Ok(closed.remaining.unwrap_or_else(|| Position {
+                    qty: dec!(0),
+                    pnl: dec!(0),
+                    margin: dec!(0),
+                    ..closed.position
+                }));
+            }
+
+            // the part beyond the existing size opens a new position under this order's id.
+            // It is checked up front, closing first and then failing would leave the user flat
+            let flipped = OpenOrderRequest {
+                qty: order.qty + existing.qty,
+                ..order
+            };
+            let plan = self.plan_open(&flipped, fill_price)?;
+
+            let quote = self.prices.live(&existing.asset)?;
+            let exit_price = if existing.qty > dec!(0) {
+                quote.bid
+            } else {
+                quote.ask
+            };
+            let settlement = existing.settle(
+                existing.qty,
+                exit_price,
+                instrument.taker_fee_rate,
+                self.margin_mode(&user_id),
+            );
+            let available = get_balance(&wallet_tx, &user_id).await? + reserved + settlement.amount;
+            if available < plan.amount_required {
+                return Err(WalletError::InsufficientFunds {
+                    currency: COLLATERAL.to_string(),
+                    available,
+                    required: plan.amount_required,
+                }
+                .into());
+            }
+
+            self.close(&user_id, position_id, None, wallet_tx.clone())
+                .await?;
+            return self
+                .open_order(user_id, flipped, fill_price, reserved, wallet_tx)
+                .await;
+        }
 
-        if balance < current_price * order.qty.abs() + margin {
+        let leverage = order.leverage.unwrap_or(existing.leverage);
+        if leverage != existing.leverage {
             return Err(format!(
-                "Not enough balance, Balance: {}, Needed: {}",
-                balance,
-                current_price * order.qty.abs() + margin,
+                "Leverage must match the open {} position's {}x",
+                existing.asset, existing.leverage
             ));
         }
 
-        let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), String>>();
-        wallet_tx
-            .send(WalletManagerMsg::Debit {
-                user_id: user_id.clone(),
-                amount: amount_required,
-                responder: oneshot_tx,
-            })
-            .map_err(|err| err.to_string())?;
+        let extra_margin = order.margin.unwrap_or(dec!(0));
+        if extra_margin < dec!(0) {
+            return Err("Margin cannot be negative".to_string());
+        }
 
-        oneshot_rx.await.map_err(|err| err.to_string())??;
+        let quote = self.prices.live(&existing.asset)?;
+        let fill_price = fill_price.unwrap_or(if order.qty > dec!(0) {
+            quote.ask
+        } else {
+            quote.bid
+        });
+
+        let trailing_stop = order
+            .trailing_stop
+            .as_ref()
+            .map(|request| TrailingStop::new(request, fill_price, existing.qty + order.qty))
+            .transpose()?;
+        order.target_mode.check_targets(
+            order.stop_loss,
+            order.take_profit,
+            existing.qty + order.qty,
+            fill_price,
+        )?;
 
-        let pnl = (current_price * order.qty) - (entry_price * order.qty);
+        let amount_required = fill_price * order.qty.abs() / leverage + extra_margin;
+        charge(&wallet_tx, &user_id, amount_required, reserved).await?;
 
-        let position = Position {
+        let position = self
+            .position_map
+            .get_mut(&user_id)
+            .and_then(|positions| positions.iter_mut().find(|p| p.position_id == position_id))
+            .ok_or_else(|| "Could not find position".to_string())?;
+
+        let qty = position.qty + order.qty;
+        // volume weighted, so pnl on the combined size matches the two fills
+        position.entry_price =
+            (position.entry_price * position.qty.abs() + fill_price * order.qty.abs()) / qty.abs();
+        position.qty = qty;
+        position.margin += amount_required;
+        position.pnl = (fill_price - position.entry_price) * position.qty;
+        if order.stop_loss.is_some() || order.take_profit.is_some() {
+            // a target left out is kept, unless it was set in the other mode
+            if order.target_mode != position.target_mode {
+                position.stop_loss = None;
+                position.take_profit = None;
+                position.target_mode = order.target_mode;
+            }
+            if order.stop_loss.is_some() {
+                position.stop_loss = order.stop_loss;
+            }
+            if order.take_profit.is_some() {
+                position.take_profit = order.take_profit;
+            }
+        }
+        if trailing_stop.is_some() {
+            position.trailing_stop = trailing_stop;
+        }
+        position.liquidation_price = position.compute_liquidation_price(maintenance_margin_ratio);
+
+        let position = position.clone();
+        self.storage.send(StorageMsg::OpenPosition {
+            user_id,
+            position: position.clone(),
+        });
+
+        Ok(position)
+    }
+
+    /// Everything about opening `order` that can fail before the wallet is charged.
+    fn plan_open(
+        &self,
+        order: &OpenOrderRequest,
+        fill_price: Option<Decimal>,
+    ) -> Result<OpenPlan, String> {
+        let instrument = self.assets.validate(order)?;
+        let leverage = order.leverage.unwrap_or(dec!(1));
+
+        let quote = self.prices.live(&instrument.symbol)?;
+        let entry_price = fill_price.unwrap_or(if order.qty < dec!(0) {
+            quote.bid
+        } else {
+            quote.ask
+        });
+
+        // optional collateral posted on top of the initial margin, pushes liquidation further out
+        let extra_margin = order.margin.unwrap_or(dec!(0));
+        if extra_margin < dec!(0) {
+            return Err("Margin cannot be negative".to_string());
+        }
+
+        // trails from the entry until the mark improves on it
+        let trailing_stop = order
+            .trailing_stop
+            .as_ref()
+            .map(|request| TrailingStop::new(request, entry_price, order.qty))
+            .transpose()?;
+        order.target_mode.check_targets(
+            order.stop_loss,
+            order.take_profit,
+            order.qty,
+            entry_price,
+        )?;
+
+        let initial_margin = entry_price * order.qty.abs() / leverage;
+
+        Ok(OpenPlan {
+            asset: instrument.symbol.clone(),
+            entry_price,
+            leverage,
+            amount_required: initial_margin
+                .checked_add(extra_margin)
+                .ok_or_else(|| format!("Margin {} is too large", extra_margin))?,
+            maintenance_margin_ratio: instrument.maintenance_margin_ratio,
+            trailing_stop,
+        })
+    }
+
+    async fn open_order(
+        &mut self,
+        user_id: String,
+        order: OpenOrderRequest,
+        fill_price: Option<Decimal>,
+        reserved: Decimal,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<Position, String> {
+        let plan = self.plan_open(&order, fill_price)?;
+        charge(&wallet_tx, &user_id, plan.amount_required, reserved).await?;
+
+        let mut position = Position {
             position_id: order.order_id.clone(),
-            asset: "BTC".to_string(),
-            entry_price: entry_price,
+            asset: plan.asset,
+            entry_price: plan.entry_price,
             qty: order.qty,
-            pnl: pnl,
-            margin: margin,
+            pnl: dec!(0),
+            margin: plan.amount_required,
             stop_loss: order.stop_loss,
             take_profit: order.take_profit,
-            leverage: order.leverage,
+            target_mode: order.target_mode,
+            leverage: plan.leverage,
+            liquidation_price: dec!(0),
+            trailing_stop: plan.trailing_stop,
         };
+        position.liquidation_price =
+            position.compute_liquidation_price(plan.maintenance_margin_ratio);
+
+        self.storage.send(StorageMsg::OpenPosition {
+            user_id: user_id.clone(),
+            position: position.clone(),
+        });
 
         match self.position_map.get_mut(&user_id.clone()) {
             Some(positions) => {
-                positions.push(position);
+                positions.push(position.clone());
             }
             None => {
-                self.position_map.insert(user_id, vec![position]);
+                self.position_map.insert(user_id, vec![position.clone()]);
             }
         };
 
-        Ok(order.order_id.clone())
+        Ok(position)
     }
 
+    /// Closes `qty` of the position, or all of it when `qty` is `None`. The remainder of a
+    /// partial close keeps its entry price.
     pub async fn close(
         &mut self,
         user_id: &String,
         position_id: String,
+        qty: Option<Decimal>,
         wallet_tx: UnboundedSender<WalletManagerMsg>,
-    ) -> Result<(), String> {
-        if !self.position_map.contains_key(user_id) {
-            return Err("Could not find user".to_string());
-        }
+    ) -> Result<ClosedPosition, String> {
+        let positions = self
+            .position_map
+            .get_mut(user_id)
+            .ok_or_else(|| "Could not find user".to_string())?;
 
-        let positions = self.position_map.get_mut(user_id).unwrap();
-        let latest_price = self.latest_price.load();
+        let position_index = positions
+            .iter()
+            .position(|position| position.position_id == position_id)
+            .ok_or_else(|| "Could not find position".to_string())?;
 
-        let mut position_index: Option<usize> = None;
+        let position = &positions[position_index];
+        let latest_price = self.prices.live(&position.asset)?;
+        let current_price = if position.qty > dec!(0) {
+            latest_price.bid
+        } else {
+            latest_price.ask
+        };
 
-        for (idx, position) in positions.iter().enumerate() {
-            if position.position_id == position_id {
-                let current_price = if position.qty > dec!(0) {
-                    latest_price.bid
+        let instrument = self
+            .assets
+            .get(&position.asset)
+            .ok_or_else(|| format!("Unknown asset {}", position.asset))?;
+
+        // close qty is unsigned, it always reduces the position towards zero
+        let close_qty = match qty {
+            Some(qty) => {
+                if qty <= dec!(0) || qty > position.qty.abs() {
+                    return Err(format!(
+                        "Close quantity must be between 0 and {}, got {}",
+                        position.qty.abs(),
+                        qty
+                    ));
+                }
+                let remaining = position.qty.abs() - qty;
+                if remaining > dec!(0) {
+                    instrument.check_qty(qty)?;
+                    instrument.check_qty(remaining)?;
+                }
+                if position.qty > dec!(0) {
+                    qty
                 } else {
-                    latest_price.ask
-                };
+                    -qty
+                }
+            }
+            None => position.qty,
+        };
 
-                let (oneshot_tx, oneshot_rx) = oneshot::channel::<Option<Decimal>>();
-                wallet_tx
-                    .send(WalletManagerMsg::GetBalance {
-                        user_id: user_id.clone(),
-                        responder: oneshot_tx,
-                    })
-                    .map_err(|x| x.to_string())?;
-
-                let balance = match oneshot_rx.await {
-                    Ok(Some(balance)) => balance,
-                    Ok(None) => return Err("Could not get balance".to_string()),
-                    Err(err) => return Err(err.to_string()),
-                };
+        let margin_mode = self.margin_modes.get(user_id).copied().unwrap_or_default();
+        let settlement = position.settle(
+            close_qty,
+            current_price,
+            instrument.taker_fee_rate,
+            margin_mode,
+        );
+        let maintenance_margin_ratio = instrument.maintenance_margin_ratio;
 
-                let new_balance = (current_price * position.qty)
-                    - (position.entry_price * position.qty)
-                    + balance;
+        let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), WalletError>>();
+        let msg = if settlement.amount >= dec!(0) {
+            WalletManagerMsg::Credit {
+                user_id: user_id.clone(),
+                currency: COLLATERAL.to_string(),
+                amount: settlement.amount,
+                responder: oneshot_tx,
+            }
+        } else {
+            WalletManagerMsg::Debit {
+                user_id: user_id.clone(),
+                currency: COLLATERAL.to_string(),
+                amount: -settlement.amount,
+                responder: oneshot_tx,
+            }
+        };
+        wallet_tx.send(msg).map_err(|x| x.to_string())?;
 
-                let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), String>>();
-                wallet_tx
-                    .send(WalletManagerMsg::Credit {
-                        user_id: user_id.clone(),
-                        amount: new_balance,
-                        responder: oneshot_tx,
-                    })
-                    .map_err(|x| x.to_string())?;
+        oneshot_rx
+            .await
+            .map_err(|_| "[POSITIONS CLOSE ERROR] oneshot recv channel closed")??;
 
-                oneshot_rx
-                    .await
-                    .map_err(|_| "[POSITIONS CLOSE ERROR] oneshot recv channel closed")??;
+        if close_qty == positions[position_index].qty {
+            let position = positions.remove(position_index);
+
+            let closed = ClosedPosition {
+                position,
+                settlement,
+                remaining: None,
+            };
+
+            self.storage.send(StorageMsg::ClosePosition {
+                user_id: user_id.clone(),
+                closed: closed.clone(),
+            });
+
+            return Ok(closed);
+        }
+
+        let position = &mut positions[position_index];
+        let mut closed_portion = position.clone();
+        closed_portion.qty = settlement.qty;
+        closed_portion.margin = settlement.returned_margin;
+        closed_portion.pnl = settlement.realized_pnl;
+
+        position.qty -= settlement.qty;
+        position.margin -= settlement.returned_margin;
+        position.pnl = (current_price - position.entry_price) * position.qty;
+        position.liquidation_price = position.compute_liquidation_price(maintenance_margin_ratio);
+
+        let closed = ClosedPosition {
+            position: closed_portion,
+            settlement,
+            remaining: Some(position.clone()),
+        };
+
+        self.storage.send(StorageMsg::ReducePosition {
+            user_id: user_id.clone(),
+            closed: closed.clone(),
+        });
+
+        Ok(closed)
+    }
+
+    /// Replaces both targets of an open position, `None` clears one.
+    pub async fn set_targets(
+        &mut self,
+        user_id: String,
+        position_id: String,
+        target_mode: TargetMode,
+        stop_loss: Option<Decimal>,
+        take_profit: Option<Decimal>,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<Position, String> {
+        let changes = PositionChanges {
+            target_mode: Some(target_mode),
+            stop_loss,
+            take_profit,
+            clear_stop_loss: stop_loss.is_none(),
+            clear_take_profit: take_profit.is_none(),
+            margin_delta: None,
+        };
+
+        self.modify(user_id, position_id, changes, wallet_tx).await
+    }
+
+    /// Applies `changes` to an open position. Everything is checked before the wallet is
+    /// touched, and margin can only be removed while equity stays above maintenance.
+    pub async fn modify(
+        &mut self,
+        user_id: String,
+        position_id: String,
+        changes: PositionChanges,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Result<Position, String> {
+        let mut position = self
+            .position_map
+            .get(&user_id)
+            .and_then(|positions| positions.iter().find(|p| p.position_id == position_id))
+            .cloned()
+            .ok_or_else(|| "Could not find position".to_string())?;
+
+        let maintenance_margin_ratio = self
+            .assets
+            .get(&position.asset)
+            .ok_or_else(|| format!("Unknown asset {}", position.asset))?
+            .maintenance_margin_ratio;
+
+        let quote = self.prices.live(&position.asset)?;
+        let mark_price = if position.qty > dec!(0) {
+            quote.bid
+        } else {
+            quote.ask
+        };
+        position.pnl = (mark_price - position.entry_price) * position.qty;
+
+        if changes.clear_stop_loss && changes.stop_loss.is_some() {
+            return Err("Cannot both set and clear the stop-loss".to_string());
+        }
+        if changes.clear_take_profit && changes.take_profit.is_some() {
+            return Err("Cannot both set and clear the take-profit".to_string());
+        }
+
+        if changes.target_mode.is_some()
+            || changes.stop_loss.is_some()
+            || changes.take_profit.is_some()
+            || changes.clear_stop_loss
+            || changes.clear_take_profit
+        {
+            let target_mode = changes.target_mode.unwrap_or(position.target_mode);
+            let stop_loss = if changes.clear_stop_loss {
+                None
+            } else {
+                changes.stop_loss.or(position.stop_loss)
+            };
+            let take_profit = if changes.clear_take_profit {
+                None
+            } else {
+                changes.take_profit.or(position.take_profit)
+            };
 
-                position_index = Some(idx);
+            // a kept target would be read in the other mode's units
+            if target_mode != position.target_mode
+                && ((changes.stop_loss.is_none() && stop_loss.is_some())
+                    || (changes.take_profit.is_none() && take_profit.is_some()))
+            {
+                return Err(format!(
+                    "Switching to {} targets needs both targets given or cleared",
+                    target_mode.as_str()
+                ));
             }
+            target_mode.check_targets(stop_loss, take_profit, position.qty, mark_price)?;
+
+            position.target_mode = target_mode;
+            position.stop_loss = stop_loss;
+            position.take_profit = take_profit;
         }
 
-        if let Some(idx) = position_index {
-            positions.remove(idx);
+        let margin_delta = changes.margin_delta.unwrap_or(dec!(0));
+        if margin_delta != dec!(0) {
+            if self.margin_mode(&user_id) != MarginMode::Isolated {
+                return Err("Margin can only be adjusted on isolated positions".to_string());
+            }
+
+            position.margin += margin_delta;
+            position.liquidation_price =
+                position.compute_liquidation_price(maintenance_margin_ratio);
+
+            if margin_delta < dec!(0) {
+                let maintenance_margin = maintenance_margin_ratio * position.notional(mark_price);
+                if position.margin <= dec!(0) || position.margin + position.pnl < maintenance_margin
+                {
+                    return Err(format!(
+                        "Removing {} margin leaves equity below the maintenance margin of {}",
+                        -margin_delta, maintenance_margin
+                    ));
+                }
+
+                credit(&wallet_tx, &user_id, -margin_delta).await?;
+            } else {
+                charge(&wallet_tx, &user_id, margin_delta, dec!(0)).await?;
+            }
         }
 
-        Ok(())
+        if let Some(existing) = self
+            .position_map
+            .get_mut(&user_id)
+            .and_then(|positions| positions.iter_mut().find(|p| p.position_id == position_id))
+        {
+            *existing = position.clone();
+        }
+
+        self.storage.send(StorageMsg::OpenPosition {
+            user_id,
+            position: position.clone(),
+        });
+
+        Ok(position)
     }
 
     pub fn list(&self, user_id: &String) -> Result<Vec<Position>, String> {
@@ -200,60 +1456,426 @@ impl Positions {
         }
     }
 
+    /// Marks every position to market, ratchets trailing stops and closes the ones that hit
+    /// liquidation, a stop or take-profit, returning one event per forced close.
     pub async fn update_risk(
         &mut self,
         wallet_tx: UnboundedSender<WalletManagerMsg>,
-    ) -> Result<(), String> {
-        let latest_price = self.latest_price.load();
-        let mut positions_to_liquidate: Vec<(String, String)> = Vec::new(); // vec of position_ids
+    ) -> Vec<RiskEvent> {
+        // each position is queued at most once, with the first reason that triggered
+        let mut positions_to_close: Vec<(String, String, CloseReason)> = Vec::new();
+        // cross users' surviving positions as (position_id, margin, pnl, maintenance margin)
+        let mut cross_accounts: HashMap<String, Vec<(String, Decimal, Decimal, Decimal)>> =
+            HashMap::new();
+        // positions whose trailing stop moved, persisted so a restart doesn't lose the ratchet
+        let mut ratcheted: Vec<(String, Position)> = Vec::new();
 
         for (user_id, positions) in self.position_map.iter_mut() {
+            let margin_mode = self.margin_modes.get(user_id).copied().unwrap_or_default();
+
             for position in positions {
-                let current_price = if position.qty < dec!(0) {
+                // positions in an asset without a live quote are left as-is until one arrives
+                let latest_price = match self.prices.live(&position.asset) {
+                    Ok(quote) => quote,
+                    Err(_) => continue,
+                };
+
+                // mark at the side of the book the position would close against
+                let current_price = if position.qty > dec!(0) {
                     latest_price.bid
                 } else {
                     latest_price.ask
                 };
 
-                position.pnl = (current_price - position.entry_price)
-                    * position.qty
-                    * position.leverage.unwrap_or(dec!(1));
+                // leverage is already in the size of the position, it doesn't scale pnl
+                position.pnl = (current_price - position.entry_price) * position.qty;
 
-                let initial_margin = (position.entry_price * position.qty.abs()) + position.margin;
+                let qty = position.qty;
+                if position
+                    .trailing_stop
+                    .as_mut()
+                    .is_some_and(|trailing_stop| trailing_stop.ratchet(current_price, qty))
+                {
+                    ratcheted.push((user_id.clone(), position.clone()));
+                }
+                let trailing_stop_hit = position
+                    .trailing_stop
+                    .as_ref()
+                    .is_some_and(|trailing_stop| trailing_stop.is_hit(current_price, qty));
 
-                let current_margin = initial_margin + position.pnl;
+                let reason = if margin_mode == MarginMode::Isolated
+                    && position.is_liquidatable(current_price)
+                {
+                    Some(CloseReason::Liquidation)
+                } else if position.is_stop_loss_hit(current_price) {
+                    Some(CloseReason::StopLoss)
+                } else if trailing_stop_hit {
+                    Some(CloseReason::TrailingStop)
+                } else if position.is_take_profit_hit(current_price) {
+                    Some(CloseReason::TakeProfit)
+                } else {
+                    None
+                };
 
-                if initial_margin * LIQUIDATION_THRESHOLD >= current_margin {
-                    positions_to_liquidate.push((user_id.clone(), position.position_id.clone()));
+                match reason {
+                    Some(reason) => positions_to_close.push((
+                        user_id.clone(),
+                        position.position_id.clone(),
+                        reason,
+                    )),
+                    None if margin_mode == MarginMode::Cross => {
+                        let maintenance_margin = match self.assets.get(&position.asset) {
+                            Some(instrument) => {
+                                instrument.maintenance_margin_ratio
+                                    * position.notional(current_price)
+                            }
+                            None => continue,
+                        };
+                        cross_accounts.entry(user_id.clone()).or_default().push((
+                            position.position_id.clone(),
+                            position.margin,
+                            position.pnl,
+                            maintenance_margin,
+                        ));
+                    }
+                    None => {}
                 }
+            }
+        }
 
-                if let Some(stop_loss_threshold) = position.stop_loss {
-                    if position.pnl <= stop_loss_threshold {
-                        positions_to_liquidate
-                            .push((user_id.clone(), position.position_id.clone()));
-                    }
+        for (user_id, position) in ratcheted {
+            self.storage
+                .send(StorageMsg::OpenPosition { user_id, position });
+        }
+
+        // stops and isolated liquidations settle first, so the balances read for cross
+        // equity below already include what they paid back
+        let mut events = Vec::with_capacity(positions_to_close.len());
+        for (user_id, position_id, reason) in positions_to_close {
+            if let Some(event) = self
+                .force_close(user_id, position_id, reason, wallet_tx.clone())
+                .await
+            {
+                events.push(event);
+            }
+        }
+
+        for (user_id, mut positions) in cross_accounts {
+            let balance = match get_balance(&wallet_tx, &user_id).await {
+                Ok(balance) => balance,
+                Err(err) => {
+                    eprintln!("[UPDATE RISK] no balance for {}: {}", user_id, err);
+                    continue;
                 }
+            };
 
-                if let Some(take_profit_threshold) = position.take_profit {
-                    if position.pnl >= take_profit_threshold {
-                        positions_to_liquidate
-                            .push((user_id.clone(), position.position_id.clone()));
-                    }
+            let equity = balance
+                + positions
+                    .iter()
+                    .map(|(_, margin, pnl, _)| margin + pnl)
+                    .sum::<Decimal>();
+            let mut requirement = positions
+                .iter()
+                .map(|(_, _, _, maintenance_margin)| *maintenance_margin)
+                .sum::<Decimal>();
+
+            // biggest unrealized loss goes first, until the rest is covered again
+            positions.sort_by_key(|(_, _, pnl, _)| *pnl);
+            for (position_id, _, _, maintenance_margin) in positions {
+                if equity >= requirement {
+                    break;
+                }
+                requirement -= maintenance_margin;
+                if let Some(event) = self
+                    .force_close(
+                        user_id.clone(),
+                        position_id,
+                        CloseReason::Liquidation,
+                        wallet_tx.clone(),
+                    )
+                    .await
+                {
+                    events.push(event);
                 }
             }
         }
 
-        for (user_id, position_id) in positions_to_liquidate {
-            let positions = self.position_map.get(&user_id).unwrap();
-            let position = positions
-                .iter()
-                .find(|p| p.position_id == position_id)
-                .unwrap();
+        events
+    }
 
-            self.close(&user_id, position.position_id.clone(), wallet_tx.clone())
-                .await?;
+    async fn force_close(
+        &mut self,
+        user_id: String,
+        position_id: String,
+        reason: CloseReason,
+        wallet_tx: UnboundedSender<WalletManagerMsg>,
+    ) -> Option<RiskEvent> {
+        match self
+            .close(&user_id, position_id.clone(), None, wallet_tx)
+            .await
+        {
+            Ok(closed) => Some(RiskEvent {
+                user_id,
+                position_id,
+                asset: closed.position.asset,
+                reason,
+                qty: closed.position.qty,
+                entry_price: closed.position.entry_price,
+                exit_price: closed.settlement.exit_price,
+                realized_pnl: closed.settlement.realized_pnl,
+                fees: closed.settlement.fees,
+                timestamp: Utc::now(),
+            }),
+            Err(err) => {
+                eprintln!("[UPDATE RISK] could not close {}: {}", position_id, err);
+                None
+            }
         }
+    }
+}
 
-        Ok(())
+/// The user's available collateral.
+async fn get_balance(
+    wallet_tx: &UnboundedSender<WalletManagerMsg>,
+    user_id: &str,
+) -> Result<Decimal, String> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Option<Wallet>>();
+    wallet_tx
+        .send(WalletManagerMsg::GetBalance {
+            user_id: user_id.to_string(),
+            responder: oneshot_tx,
+        })
+        .map_err(|err| err.to_string())?;
+
+    oneshot_rx
+        .await
+        .map_err(|err| err.to_string())?
+        .map(|wallet| wallet.balance(COLLATERAL).available)
+        .ok_or_else(|| "Wallet not found".to_string())
+}
+
+async fn credit(
+    wallet_tx: &UnboundedSender<WalletManagerMsg>,
+    user_id: &str,
+    amount: Decimal,
+) -> Result<(), String> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), WalletError>>();
+    wallet_tx
+        .send(WalletManagerMsg::Credit {
+            user_id: user_id.to_string(),
+            currency: COLLATERAL.to_string(),
+            amount,
+            responder: oneshot_tx,
+        })
+        .map_err(|err| err.to_string())?;
+
+    Ok(oneshot_rx.await.map_err(|err| err.to_string())??)
+}
+
+/// Takes `amount` from the wallet in one step, out of a resting order's `reserved` hold
+/// first when there is one.
+async fn charge(
+    wallet_tx: &UnboundedSender<WalletManagerMsg>,
+    user_id: &str,
+    amount: Decimal,
+    reserved: Decimal,
+) -> Result<(), String> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), WalletError>>();
+    let msg = if reserved > dec!(0) {
+        WalletManagerMsg::Commit {
+            user_id: user_id.to_string(),
+            currency: COLLATERAL.to_string(),
+            reserved,
+            amount,
+            responder: oneshot_tx,
+        }
+    } else {
+        WalletManagerMsg::TryDebit {
+            user_id: user_id.to_string(),
+            currency: COLLATERAL.to_string(),
+            amount,
+            responder: oneshot_tx,
+        }
+    };
+    wallet_tx.send(msg).map_err(|err| err.to_string())?;
+
+    Ok(oneshot_rx.await.map_err(|err| err.to_string())??)
+}
+
+async fn reserve(
+    wallet_tx: &UnboundedSender<WalletManagerMsg>,
+    user_id: &str,
+    amount: Decimal,
+) -> Result<(), String> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), WalletError>>();
+    wallet_tx
+        .send(WalletManagerMsg::Reserve {
+            user_id: user_id.to_string(),
+            currency: COLLATERAL.to_string(),
+            amount,
+            responder: oneshot_tx,
+        })
+        .map_err(|err| err.to_string())?;
+
+    Ok(oneshot_rx.await.map_err(|err| err.to_string())??)
+}
+
+async fn release(
+    wallet_tx: &UnboundedSender<WalletManagerMsg>,
+    user_id: &str,
+    amount: Decimal,
+) -> Result<(), String> {
+    let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), WalletError>>();
+    wallet_tx
+        .send(WalletManagerMsg::Release {
+            user_id: user_id.to_string(),
+            currency: COLLATERAL.to_string(),
+            amount,
+            responder: oneshot_tx,
+        })
+        .map_err(|err| err.to_string())?;
+
+    Ok(oneshot_rx.await.map_err(|err| err.to_string())??)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(qty: Decimal, entry_price: Decimal, margin: Decimal) -> Position {
+        Position {
+            position_id: "p1".to_string(),
+            asset: "BTC".to_string(),
+            entry_price,
+            qty,
+            pnl: dec!(0),
+            margin,
+            stop_loss: None,
+            take_profit: None,
+            target_mode: TargetMode::Price,
+            leverage: dec!(10),
+            liquidation_price: dec!(0),
+            trailing_stop: None,
+        }
+    }
+
+    #[test]
+    fn settle_credits_margin_plus_pnl_minus_fees() {
+        let position = position(dec!(1), dec!(100), dec!(10));
+
+        for margin_mode in [MarginMode::Isolated, MarginMode::Cross] {
+            let settlement = position.settle(dec!(1), dec!(110), dec!(0.001), margin_mode);
+
+            assert_eq!(settlement.returned_margin, dec!(10));
+            assert_eq!(settlement.realized_pnl, dec!(10));
+            assert_eq!(settlement.fees, dec!(0.11));
+            assert_eq!(settlement.amount, dec!(19.89));
+        }
+    }
+
+    #[test]
+    fn settle_floors_isolated_loss_at_the_margin() {
+        let position = position(dec!(1), dec!(100), dec!(10));
+
+        let settlement = position.settle(dec!(1), dec!(80), dec!(0.001), MarginMode::Isolated);
+
+        assert_eq!(settlement.realized_pnl, dec!(-20));
+        assert_eq!(settlement.amount, dec!(0));
+    }
+
+    #[test]
+    fn settle_debits_cross_loss_beyond_the_margin() {
+        let position = position(dec!(1), dec!(100), dec!(10));
+
+        let settlement = position.settle(dec!(1), dec!(80), dec!(0.001), MarginMode::Cross);
+
+        assert_eq!(settlement.amount, dec!(-10.08));
+    }
+
+    #[test]
+    fn settle_partial_close_is_proportional() {
+        let position = position(dec!(2), dec!(100), dec!(20));
+
+        let settlement = position.settle(dec!(0.5), dec!(110), dec!(0.001), MarginMode::Isolated);
+
+        assert_eq!(settlement.qty, dec!(0.5));
+        assert_eq!(settlement.returned_margin, dec!(5));
+        assert_eq!(settlement.realized_pnl, dec!(5));
+        assert_eq!(settlement.fees, dec!(0.055));
+        assert_eq!(settlement.amount, dec!(9.945));
+    }
+
+    // equity left at `price` equals the maintenance margin there
+    fn assert_at_maintenance(
+        position: &Position,
+        price: Decimal,
+        maintenance_margin_ratio: Decimal,
+    ) {
+        let equity = position.margin + (price - position.entry_price) * position.qty;
+        let maintenance = maintenance_margin_ratio * price * position.qty.abs();
+        assert!((equity - maintenance).abs() < dec!(0.000000001));
+    }
+
+    #[test]
+    fn liquidation_price_of_a_long_is_below_entry_at_maintenance() {
+        let position = position(dec!(2), dec!(100), dec!(20));
+
+        let price = position.compute_liquidation_price(dec!(0.005));
+
+        assert!(price < dec!(100));
+        assert_at_maintenance(&position, price, dec!(0.005));
+    }
+
+    #[test]
+    fn liquidation_price_of_a_short_is_above_entry_at_maintenance() {
+        let position = position(dec!(-2), dec!(100), dec!(20));
+
+        let price = position.compute_liquidation_price(dec!(0.005));
+
+        assert!(price > dec!(100));
+        assert_at_maintenance(&position, price, dec!(0.005));
+    }
+
+    #[test]
+    fn fully_collateralized_long_has_no_liquidation_price() {
+        let position = position(dec!(2), dec!(100), dec!(200));
+
+        assert_eq!(position.compute_liquidation_price(dec!(0.005)), dec!(0));
+    }
+
+    fn trail(kind: TrailKind, value: Decimal, entry_price: Decimal, qty: Decimal) -> TrailingStop {
+        TrailingStop::new(&TrailingStopRequest { kind, value }, entry_price, qty).unwrap()
+    }
+
+    #[test]
+    fn long_trailing_stop_only_ratchets_up() {
+        let mut trailing_stop = trail(TrailKind::Distance, dec!(5), dec!(100), dec!(1));
+        assert_eq!(trailing_stop.trigger_price, dec!(95));
+
+        assert!(trailing_stop.ratchet(dec!(110), dec!(1)));
+        assert_eq!(trailing_stop.trigger_price, dec!(105));
+
+        assert!(!trailing_stop.ratchet(dec!(104), dec!(1)));
+        assert_eq!(trailing_stop.best_price, dec!(110));
+        assert_eq!(trailing_stop.trigger_price, dec!(105));
+
+        assert!(!trailing_stop.is_hit(dec!(106), dec!(1)));
+        assert!(trailing_stop.is_hit(dec!(105), dec!(1)));
+    }
+
+    #[test]
+    fn short_trailing_stop_only_ratchets_down() {
+        let mut trailing_stop = trail(TrailKind::Percent, dec!(10), dec!(100), dec!(-1));
+        assert_eq!(trailing_stop.trigger_price, dec!(110));
+
+        assert!(trailing_stop.ratchet(dec!(80), dec!(-1)));
+        assert_eq!(trailing_stop.trigger_price, dec!(88));
+
+        assert!(!trailing_stop.ratchet(dec!(85), dec!(-1)));
+        assert_eq!(trailing_stop.best_price, dec!(80));
+        assert_eq!(trailing_stop.trigger_price, dec!(88));
+
+        assert!(!trailing_stop.is_hit(dec!(87), dec!(-1)));
+        assert!(trailing_stop.is_hit(dec!(88), dec!(-1)));
     }
 }
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+
+use crate::types::types::{CurrentPrice, IncomingPrices};
+
+// quotes older than this are considered stale and are not traded on
+const MAX_QUOTE_AGE_MS: i64 = 5_000;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Quote {
+    pub bid: Decimal,
+    pub ask: Decimal,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Quote {
+    pub fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        now - self.updated_at > Duration::milliseconds(MAX_QUOTE_AGE_MS)
+    }
+}
+
+/// Latest bid/ask per asset symbol, swapped in as a whole so readers always
+/// see a consistent set of quotes.
+#[derive(Debug)]
+pub struct PriceStore {
+    quotes: ArcSwap<HashMap<String, Quote>>,
+}
+
+impl PriceStore {
+    pub fn new() -> PriceStore {
+        PriceStore {
+            quotes: ArcSwap::from_pointee(HashMap::new()),
+        }
+    }
+
+    /// Stores the quotes in `prices` as of `sent_at`, the producer's timestamp. It is capped
+    /// at the local clock so a producer running ahead can't keep its quotes fresh forever.
+    pub fn update(&self, prices: IncomingPrices, sent_at: DateTime<Utc>) {
+        let updated_at = sent_at.min(Utc::now());
+        let incoming: [(&str, CurrentPrice); 3] = [
+            ("BTC", prices.btc),
+            ("ETH", prices.eth),
+            ("SOL", prices.sol),
+        ];
+
+        self.quotes.rcu(|current| {
+            let mut quotes = HashMap::clone(current);
+            for (symbol, price) in incoming.iter() {
+                quotes.insert(
+                    symbol.to_string(),
+                    Quote {
+                        bid: price.bid,
+                        ask: price.ask,
+                        updated_at,
+                    },
+                );
+            }
+            quotes
+        });
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<Quote> {
+        self.quotes.load().get(&symbol.to_uppercase()).cloned()
+    }
+
+    /// Returns the quote for `symbol` only if it is usable for trading right now.
+    pub fn live(&self, symbol: &str) -> Result<Quote, String> {
+        let quote = self
+            .get(symbol)
+            .ok_or_else(|| format!("No price available for {}", symbol))?;
+
+        if quote.bid <= dec!(0) || quote.ask <= dec!(0) {
+            return Err("Could not process order, server error".to_string());
+        }
+
+        if quote.is_stale(Utc::now()) {
+            return Err(format!(
+                "Price for {} is stale, last update at {}",
+                symbol, quote.updated_at
+            ));
+        }
+
+        Ok(quote)
+    }
+}
@@ -2,13 +2,43 @@ use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
 
-use crate::types::positions::Position;
+use crate::types::{
+    orders::{NettedOrder, OrderStatus, PendingOrder},
+    positions::{
+        ClosedPosition, MarginMode, Position, PositionChanges, PositionMode, TargetMode,
+        TrailingStopRequest,
+    },
+    users::User,
+    wallet::{Balance, Wallet, WalletError},
+};
 
 //
 // === Domain Models ===
 //
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    #[default]
+    Market,
+    Limit,
+    // enter at market once the mark crosses `trigger_price`
+    StopMarket,
+    // becomes a limit order at `price` once the mark crosses `trigger_price`
+    StopLimit,
+}
+
+// fills are all-or-nothing against the top of the book, so IOC and FOK behave the same
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    #[default]
+    Gtc,
+    Ioc,
+    Fok,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct OpenOrderRequest {
     pub order_id: String,
     pub user_id: String,
@@ -17,44 +47,446 @@ pub struct OpenOrderRequest {
     pub margin: Option<Decimal>,
     pub stop_loss: Option<Decimal>,
     pub take_profit: Option<Decimal>,
+    #[serde(default)]
+    pub target_mode: TargetMode,
     pub leverage: Option<Decimal>,
+    #[serde(default)]
+    pub order_type: OrderType,
+    // limit price, required for limit and stop-limit orders
+    pub price: Option<Decimal>,
+    // required for stop orders
+    pub trigger_price: Option<Decimal>,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    pub trailing_stop: Option<TrailingStopRequest>,
 }
 
-#[derive(Deserialize, Clone)]
+// order_id is the id of the order that opened the position
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CloseOrderRequest {
+    // replies are keyed by it, missing from commands journaled before that
+    #[serde(default)]
+    pub request_id: String,
     pub order_id: String,
+    pub user_id: String,
+    // closes the whole position when missing
+    pub qty: Option<Decimal>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListPositionsRequest {
+    pub request_id: String,
+    pub user_id: String,
+}
+
+// published to `orderResponses` keyed by order_id
 #[derive(Serialize, Clone)]
 pub struct OpenOrderResponse {
     pub order_id: String,
+    pub success: bool,
+    pub status: Option<&'static str>,
+    pub error: Option<String>,
+    pub entry_price: Option<Decimal>,
+    pub position: Option<Position>,
+}
+
+impl OpenOrderResponse {
+    pub fn new(order_id: String, result: Result<OrderStatus, String>) -> OpenOrderResponse {
+        match result {
+            Ok(status) => {
+                let status_str = status.as_str();
+                let position = match status {
+                    OrderStatus::Filled(position) => Some(*position),
+                    OrderStatus::Resting | OrderStatus::Expired => None,
+                };
+
+                OpenOrderResponse {
+                    order_id,
+                    success: true,
+                    status: Some(status_str),
+                    error: None,
+                    entry_price: position.as_ref().map(|position| position.entry_price),
+                    position,
+                }
+            }
+            Err(err) => OpenOrderResponse {
+                order_id,
+                success: false,
+                status: None,
+                error: Some(err),
+                entry_price: None,
+                position: None,
+            },
+        }
+    }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CancelOrderRequest {
+    // replies are keyed by it, missing from commands journaled before that
+    #[serde(default)]
+    pub request_id: String,
+    pub order_id: String,
+    pub user_id: String,
+}
+
+// published to `orderResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct CancelOrderResponse {
+    pub request_id: String,
+    pub order_id: String,
+    pub success: bool,
+    pub error: Option<String>,
+    // margin handed back to the wallet
+    pub released: Option<Decimal>,
+}
+
+impl CancelOrderResponse {
+    pub fn new(
+        request_id: String,
+        order_id: String,
+        result: Result<PendingOrder, String>,
+    ) -> CancelOrderResponse {
+        match result {
+            Ok(pending) => CancelOrderResponse {
+                request_id,
+                order_id,
+                success: true,
+                error: None,
+                released: Some(pending.reserved),
+            },
+            Err(err) => CancelOrderResponse {
+                request_id,
+                order_id,
+                success: false,
+                error: Some(err),
+                released: None,
+            },
+        }
+    }
+}
+
+// published to `orderResponses` keyed by request_id
 #[derive(Serialize, Clone)]
 pub struct CloseOrderResponse {
+    pub request_id: String,
+    pub order_id: String,
+    pub success: bool,
     pub message: String,
+    pub closed: Option<ClosedPosition>,
+}
+
+impl CloseOrderResponse {
+    pub fn new(
+        request_id: String,
+        order_id: String,
+        result: Result<ClosedPosition, String>,
+    ) -> CloseOrderResponse {
+        match result {
+            Ok(closed) => CloseOrderResponse {
+                request_id,
+                order_id,
+                success: true,
+                message: "Position closed".to_string(),
+                closed: Some(closed),
+            },
+            Err(err) => CloseOrderResponse {
+                request_id,
+                order_id,
+                success: false,
+                message: err,
+                closed: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetMarginModeRequest {
+    pub request_id: String,
+    pub user_id: String,
+    pub mode: MarginMode,
+}
+
+// published to `userResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct SetMarginModeResponse {
+    pub request_id: String,
+    pub success: bool,
+    pub mode: Option<MarginMode>,
+    pub error: Option<String>,
+}
+
+impl SetMarginModeResponse {
+    pub fn new(request_id: String, result: Result<MarginMode, String>) -> SetMarginModeResponse {
+        match result {
+            Ok(mode) => SetMarginModeResponse {
+                request_id,
+                success: true,
+                mode: Some(mode),
+                error: None,
+            },
+            Err(err) => SetMarginModeResponse {
+                request_id,
+                success: false,
+                mode: None,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetPositionModeRequest {
+    pub request_id: String,
+    pub user_id: String,
+    pub mode: PositionMode,
+}
+
+// published to `userResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct SetPositionModeResponse {
+    pub request_id: String,
+    pub success: bool,
+    pub mode: Option<PositionMode>,
+    pub error: Option<String>,
+}
+
+impl SetPositionModeResponse {
+    pub fn new(
+        request_id: String,
+        result: Result<PositionMode, String>,
+    ) -> SetPositionModeResponse {
+        match result {
+            Ok(mode) => SetPositionModeResponse {
+                request_id,
+                success: true,
+                mode: Some(mode),
+                error: None,
+            },
+            Err(err) => SetPositionModeResponse {
+                request_id,
+                success: false,
+                mode: None,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetTargetsRequest {
+    pub request_id: String,
+    pub user_id: String,
+    pub position_id: String,
+    #[serde(default)]
+    pub target_mode: TargetMode,
+    // both targets are replaced, a missing one is cleared
+    pub stop_loss: Option<Decimal>,
+    pub take_profit: Option<Decimal>,
+}
+
+// published to `orderResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct SetTargetsResponse {
+    pub request_id: String,
+    pub success: bool,
+    pub position: Option<Position>,
+    pub error: Option<String>,
+}
+
+impl SetTargetsResponse {
+    pub fn new(request_id: String, result: Result<Position, String>) -> SetTargetsResponse {
+        match result {
+            Ok(position) => SetTargetsResponse {
+                request_id,
+                success: true,
+                position: Some(position),
+                error: None,
+            },
+            Err(err) => SetTargetsResponse {
+                request_id,
+                success: false,
+                position: None,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModifyPositionRequest {
+    pub request_id: String,
+    pub user_id: String,
+    pub position_id: String,
+    // fields left out are unchanged
+    pub target_mode: Option<TargetMode>,
+    pub stop_loss: Option<Decimal>,
+    pub take_profit: Option<Decimal>,
+    #[serde(default)]
+    pub clear_stop_loss: bool,
+    #[serde(default)]
+    pub clear_take_profit: bool,
+    // positive adds isolated margin, negative removes it
+    pub margin_delta: Option<Decimal>,
+}
+
+// published to `orderResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct ModifyPositionResponse {
+    pub request_id: String,
+    pub success: bool,
+    pub position: Option<Position>,
+    pub error: Option<String>,
+}
+
+impl ModifyPositionResponse {
+    pub fn new(request_id: String, result: Result<Position, String>) -> ModifyPositionResponse {
+        match result {
+            Ok(position) => ModifyPositionResponse {
+                request_id,
+                success: true,
+                position: Some(position),
+                error: None,
+            },
+            Err(err) => ModifyPositionResponse {
+                request_id,
+                success: false,
+                position: None,
+                error: Some(err),
+            },
+        }
+    }
 }
 
+// published to `orderResponses` keyed by request_id
 #[derive(Serialize, Clone)]
 pub struct GetListResponse {
+    pub request_id: String,
     pub positions: Vec<Position>,
 }
 
-#[derive(Deserialize, Clone)]
+// the poller publishes upper-case symbols, i.e. {"BTC": {...}, "ETH": ..., "SOL": ...}
+#[derive(Serialize, Deserialize, Clone)]
 pub struct IncomingPrices {
+    #[serde(alias = "BTC")]
     pub btc: CurrentPrice,
+    #[serde(alias = "ETH")]
     pub eth: CurrentPrice,
+    #[serde(alias = "SOL")]
     pub sol: CurrentPrice,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SignUpRequest {
+    pub request_id: String,
     pub email: String,
 }
 
+// published to `userResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct CreateUserResponse {
+    pub request_id: String,
+    pub success: bool,
+    pub user_id: Option<String>,
+    pub error: Option<String>,
+}
+
+impl CreateUserResponse {
+    pub fn new(request_id: String, result: Result<String, String>) -> CreateUserResponse {
+        match result {
+            Ok(user_id) => CreateUserResponse {
+                request_id,
+                success: true,
+                user_id: Some(user_id),
+                error: None,
+            },
+            Err(err) => CreateUserResponse {
+                request_id,
+                success: false,
+                user_id: None,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+// funds one currency of a wallet, the only way to get a non-collateral balance
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DepositRequest {
+    pub request_id: String,
+    pub user_id: String,
+    pub currency: String,
+    pub amount: Decimal,
+}
+
+// published to `userResponses` keyed by request_id
+#[derive(Serialize, Clone)]
+pub struct DepositResponse {
+    pub request_id: String,
+    pub success: bool,
+    pub currency: String,
+    pub balance: Option<Balance>,
+    pub error: Option<String>,
+}
+
+impl DepositResponse {
+    pub fn new(
+        request_id: String,
+        currency: String,
+        result: Result<Balance, String>,
+    ) -> DepositResponse {
+        match result {
+            Ok(balance) => DepositResponse {
+                request_id,
+                success: true,
+                currency,
+                balance: Some(balance),
+                error: None,
+            },
+            Err(err) => DepositResponse {
+                request_id,
+                success: false,
+                currency,
+                balance: None,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+// tagged like the kafka envelope, also how commands are written to the journal
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", content = "payload", rename_all = "camelCase")]
 pub enum KafkaMessages {
+    #[serde(rename = "price")]
     IncomingPrices(IncomingPrices),
     Order(OpenOrderRequest),
+    CloseOrder(CloseOrderRequest),
+    CancelOrder(CancelOrderRequest),
+    ListPositions(ListPositionsRequest),
     CreateUser(SignUpRequest),
-    InvalidMessage,
+    SetMarginMode(SetMarginModeRequest),
+    SetPositionMode(SetPositionModeRequest),
+    SetTargets(SetTargetsRequest),
+    ModifyPosition(ModifyPositionRequest),
+    Deposit(DepositRequest),
+}
+
+impl KafkaMessages {
+    /// Request id of commands that move money and aren't idempotent on their own, a
+    /// redelivered one is answered with its recorded reply instead of running again.
+    pub fn request_id(&self) -> Option<&str> {
+        let request_id = match self {
+            KafkaMessages::CloseOrder(close) => &close.request_id,
+            KafkaMessages::CancelOrder(cancel) => &cancel.request_id,
+            KafkaMessages::ModifyPosition(request) => &request.request_id,
+            KafkaMessages::Deposit(request) => &request.request_id,
+            _ => return None,
+        };
+
+        Some(request_id.as_str()).filter(|request_id| !request_id.is_empty())
+    }
 }
 
 //
@@ -62,12 +494,15 @@ pub enum KafkaMessages {
 //
 
 pub struct CreateUserMessage {
-    pub username: String,
+    pub email: String,
     pub responder: oneshot::Sender<Result<String, String>>, // returns user_id or error
 }
 
 pub enum UserManagerMsg {
     Create(CreateUserMessage),
+    Snapshot {
+        responder: oneshot::Sender<Vec<User>>,
+    },
     // Delete {
     //     username: String,
     //     responder: oneshot::Sender<Result<(), String>>,
@@ -78,25 +513,78 @@ pub enum UserManagerMsg {
     // },
 }
 
+// amounts are in `currency`, which is the USDC collateral for everything positions do
 pub enum WalletManagerMsg {
+    // every currency's available and locked balance
     GetBalance {
         user_id: String,
-        responder: oneshot::Sender<Option<Decimal>>,
+        responder: oneshot::Sender<Option<Wallet>>,
     },
     Credit {
         user_id: String,
+        currency: String,
         amount: Decimal,
-        responder: oneshot::Sender<Result<(), String>>,
+        responder: oneshot::Sender<Result<(), WalletError>>,
     },
+    // settles a loss, which may take the balance negative; spend with `TryDebit`
     Debit {
         user_id: String,
+        currency: String,
         amount: Decimal,
-        responder: oneshot::Sender<Result<(), String>>,
+        responder: oneshot::Sender<Result<(), WalletError>>,
+    },
+    TryDebit {
+        user_id: String,
+        currency: String,
+        amount: Decimal,
+        responder: oneshot::Sender<Result<(), WalletError>>,
+    },
+    // locks margin for a resting order out of the available balance
+    Reserve {
+        user_id: String,
+        currency: String,
+        amount: Decimal,
+        responder: oneshot::Sender<Result<(), WalletError>>,
+    },
+    Release {
+        user_id: String,
+        currency: String,
+        amount: Decimal,
+        responder: oneshot::Sender<Result<(), WalletError>>,
+    },
+    // spends `reserved` of the locked balance on a charge of `amount` when the order fills
+    Commit {
+        user_id: String,
+        currency: String,
+        reserved: Decimal,
+        amount: Decimal,
+        responder: oneshot::Sender<Result<(), WalletError>>,
+    },
+    // credits a positive amount and answers with the currency's balance after it
+    Deposit {
+        user_id: String,
+        currency: String,
+        amount: Decimal,
+        responder: oneshot::Sender<Result<Balance, WalletError>>,
     },
     Create {
         user_id: String,
         responder: oneshot::Sender<Result<(), String>>,
     },
+    Snapshot {
+        responder: oneshot::Sender<Vec<Wallet>>,
+    },
+}
+
+// wallets and positions captured together by the position actor, plus the journal seq they reflect
+pub struct LedgerSnapshot {
+    pub wallets: Vec<Wallet>,
+    pub positions: Vec<(String, Position)>,
+    pub margin_modes: Vec<(String, MarginMode)>,
+    pub position_modes: Vec<(String, PositionMode)>,
+    pub resting_orders: Vec<PendingOrder>,
+    pub netted_orders: Vec<NettedOrder>,
+    pub journal_seq: u64,
 }
 
 // --- PositionManager messages ---
@@ -104,28 +592,54 @@ pub enum PositionManagerMsg {
     Open {
         user_id: String,
         order: OpenOrderRequest,
-        responder: oneshot::Sender<Result<String, String>>,
+        responder: oneshot::Sender<Result<OrderStatus, String>>,
+    },
+    Cancel {
+        user_id: String,
+        order_id: String,
+        responder: oneshot::Sender<Result<PendingOrder, String>>,
     },
     Close {
         user_id: String,
         position_id: String,
-        responder: oneshot::Sender<Result<(), String>>,
+        qty: Option<Decimal>,
+        responder: oneshot::Sender<Result<ClosedPosition, String>>,
     },
     List {
         user_id: String,
         responder: oneshot::Sender<Option<Vec<Position>>>,
     },
+    SetMarginMode {
+        user_id: String,
+        mode: MarginMode,
+        responder: oneshot::Sender<Result<MarginMode, String>>,
+    },
+    SetPositionMode {
+        user_id: String,
+        mode: PositionMode,
+        responder: oneshot::Sender<Result<PositionMode, String>>,
+    },
+    SetTargets {
+        user_id: String,
+        position_id: String,
+        target_mode: TargetMode,
+        stop_loss: Option<Decimal>,
+        take_profit: Option<Decimal>,
+        responder: oneshot::Sender<Result<Position, String>>,
+    },
+    Modify {
+        user_id: String,
+        position_id: String,
+        changes: PositionChanges,
+        responder: oneshot::Sender<Result<Position, String>>,
+    },
     UpdateRisk,
+    Snapshot {
+        responder: oneshot::Sender<Result<LedgerSnapshot, String>>,
+    },
 }
 
-#[derive(Deserialize)]
-pub struct PriceUpdates {
-    pub buy: Decimal,
-    pub sell: Decimal,
-    pub symbol: String,
-}
-
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CurrentPrice {
     pub bid: Decimal,
     pub ask: Decimal,
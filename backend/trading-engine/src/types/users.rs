@@ -1,38 +1,63 @@
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, oneshot};
 
-use crate::types::types::WalletManagerMsg;
+use crate::{
+    storage::{StorageHandle, StorageMsg},
+    types::types::WalletManagerMsg,
+};
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: String,
-    pub username: String,
+    pub email: String,
 }
 
 pub struct Users {
     user_map: HashMap<String, User>,
+    // email -> user_id, so an email only ever maps to one user
+    email_map: HashMap<String, String>,
+    storage: StorageHandle,
 }
 
 impl Users {
-    pub fn new() -> Users {
+    pub fn new(storage: StorageHandle) -> Users {
         Users {
             user_map: HashMap::new(),
+            email_map: HashMap::new(),
+            storage,
         }
     }
 
+    pub fn hydrate(&mut self, users: Vec<User>) {
+        for user in users {
+            self.email_map.insert(user.email.clone(), user.id.clone());
+            self.user_map.insert(user.id.clone(), user);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<User> {
+        self.user_map.values().cloned().collect()
+    }
+
     pub async fn create_user(
         &mut self,
-        username: String,
+        email: String,
         wallet_sender: mpsc::UnboundedSender<WalletManagerMsg>,
     ) -> Result<String, String> {
+        let email = email.trim().to_lowercase();
+
+        if email.is_empty() {
+            return Err("Email cannot be empty".to_string());
+        }
+
+        // a redelivered or retried signup gets the id the first one created
+        if let Some(user_id) = self.email_map.get(&email) {
+            return Ok(user_id.clone());
+        }
+
         let user_id = nanoid::nanoid!();
-        self.user_map.insert(
-            user_id.clone(),
-            User {
-                id: user_id.to_string(),
-                username: username,
-            },
-        );
 
         let (oneshot_tx, oneshot_rx) = oneshot::channel::<Result<(), String>>();
 
@@ -43,10 +68,19 @@ impl Users {
             })
             .map_err(|_| "could not create wallet, canceling user creation".to_string())?;
 
-        oneshot_rx.await.map_err(|_| {
-            //  TODO: delete user here...
-            return "could not create wallet, canceling user creation".to_string();
-        })??;
+        oneshot_rx
+            .await
+            .map_err(|_| "could not create wallet, canceling user creation".to_string())??;
+
+        // only record the user once their wallet exists
+        let user = User {
+            id: user_id.clone(),
+            email,
+        };
+
+        self.storage.send(StorageMsg::UpsertUser(user.clone()));
+        self.email_map.insert(user.email.clone(), user_id.clone());
+        self.user_map.insert(user_id.clone(), user);
 
         Ok(user_id)
     }
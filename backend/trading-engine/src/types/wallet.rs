@@ -1,37 +1,332 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+use crate::storage::{StorageHandle, StorageMsg};
+
+// margin, fees and pnl are all settled in the collateral currency
+pub const COLLATERAL: &str = "USDC";
+pub const CURRENCIES: [&str; 4] = [COLLATERAL, "BTC", "ETH", "SOL"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Balance {
+    // free to spend or lock
+    pub available: Decimal,
+    // held for resting orders, not part of `available`
+    pub locked: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "WalletRecord")]
 pub struct Wallet {
     pub user_id: String,
-    pub balance: Decimal,
+    // keyed by currency, every wallet holds all of `CURRENCIES`
+    pub balances: BTreeMap<String, Balance>,
+}
+
+impl Wallet {
+    pub fn new(user_id: String) -> Wallet {
+        Wallet {
+            user_id,
+            balances: CURRENCIES
+                .iter()
+                .map(|currency| (currency.to_string(), Balance::default()))
+                .collect(),
+        }
+    }
+
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).copied().unwrap_or_default()
+    }
+}
+
+// wallets journaled or snapshotted before balances were per currency held a single
+// collateral `balance` plus what was `reserved` for resting orders
+#[derive(Deserialize)]
+struct WalletRecord {
+    user_id: String,
+    #[serde(default)]
+    balances: BTreeMap<String, Balance>,
+    balance: Option<Decimal>,
+    #[serde(default)]
+    reserved: Decimal,
+}
+
+impl From<WalletRecord> for Wallet {
+    fn from(record: WalletRecord) -> Wallet {
+        let mut wallet = Wallet::new(record.user_id);
+        wallet.balances.extend(record.balances);
+
+        if let Some(balance) = record.balance {
+            wallet.balances.insert(
+                COLLATERAL.to_string(),
+                Balance {
+                    available: balance,
+                    locked: record.reserved,
+                },
+            );
+        }
+
+        wallet
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalletError {
+    NotFound,
+    UnknownCurrency(String),
+    InvalidAmount(Decimal),
+    InsufficientFunds {
+        currency: String,
+        available: Decimal,
+        required: Decimal,
+    },
+    // releasing or committing more than the wallet has locked
+    InsufficientLocked {
+        currency: String,
+        locked: Decimal,
+        required: Decimal,
+    },
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::NotFound => write!(f, "Could not find wallet"),
+            WalletError::UnknownCurrency(currency) => write!(f, "Unknown currency {}", currency),
+            WalletError::InvalidAmount(amount) => write!(f, "Invalid wallet amount {}", amount),
+            WalletError::InsufficientFunds {
+                currency,
+                available,
+                required,
+            } => write!(
+                f,
+                "Not enough {} balance, Balance: {}, Needed: {}",
+                currency, available, required
+            ),
+            WalletError::InsufficientLocked {
+                currency,
+                locked,
+                required,
+            } => write!(
+                f,
+                "Not enough {} locked, Locked: {}, Needed: {}",
+                currency, locked, required
+            ),
+        }
+    }
+}
+
+impl From<WalletError> for String {
+    fn from(err: WalletError) -> String {
+        err.to_string()
+    }
+}
+
+// an operation whose result doesn't fit in a `Decimal` is rejected for its amount
+fn checked(amount: Decimal, result: Option<Decimal>) -> Result<Decimal, WalletError> {
+    result.ok_or(WalletError::InvalidAmount(amount))
 }
 
 pub struct Wallets {
     pub wallet_map: HashMap<String, Wallet>,
+    storage: StorageHandle,
 }
 
 impl Wallets {
-    pub fn new() -> Wallets {
-        return Wallets {
+    pub fn new(storage: StorageHandle) -> Wallets {
+        Wallets {
             wallet_map: HashMap::new(),
-        };
+            storage,
+        }
+    }
+
+    pub fn hydrate(&mut self, wallets: Vec<Wallet>) {
+        for wallet in wallets {
+            self.wallet_map.insert(wallet.user_id.clone(), wallet);
+        }
+    }
+
+    /// Runs `update` against one currency balance and persists the wallet if it succeeds.
+    /// `update` must check before it changes anything, so a failed operation is a no-op.
+    fn apply(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amounts: &[Decimal],
+        update: impl FnOnce(&mut Balance) -> Result<(), WalletError>,
+    ) -> Result<(), WalletError> {
+        if let Some(amount) = amounts.iter().find(|amount| **amount < dec!(0)) {
+            return Err(WalletError::InvalidAmount(*amount));
+        }
+
+        let wallet = self
+            .wallet_map
+            .get_mut(user_id)
+            .ok_or(WalletError::NotFound)?;
+        let balance = wallet
+            .balances
+            .get_mut(currency)
+            .ok_or_else(|| WalletError::UnknownCurrency(currency.to_string()))?;
+
+        update(balance)?;
+
+        let wallet = wallet.clone();
+        self.storage.send(StorageMsg::UpsertWallet(wallet));
+
+        Ok(())
+    }
+
+    pub fn credit(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.apply(user_id, currency, &[amount], |balance| {
+            balance.available = checked(amount, balance.available.checked_add(amount))?;
+            Ok(())
+        })
+    }
+
+    /// Settles a loss without checking funds, so it may take the balance negative.
+    pub fn debit(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.apply(user_id, currency, &[amount], |balance| {
+            balance.available = checked(amount, balance.available.checked_sub(amount))?;
+            Ok(())
+        })
+    }
+
+    /// Debits `amount` only if the available balance covers it.
+    pub fn try_debit(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.apply(user_id, currency, &[amount], |balance| {
+            if balance.available < amount {
+                return Err(WalletError::InsufficientFunds {
+                    currency: currency.to_string(),
+                    available: balance.available,
+                    required: amount,
+                });
+            }
+
+            balance.available -= amount;
+            Ok(())
+        })
+    }
+
+    /// Moves `amount` from the available balance into the locked one.
+    pub fn reserve(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.apply(user_id, currency, &[amount], |balance| {
+            if balance.available < amount {
+                return Err(WalletError::InsufficientFunds {
+                    currency: currency.to_string(),
+                    available: balance.available,
+                    required: amount,
+                });
+            }
+
+            let locked = checked(amount, balance.locked.checked_add(amount))?;
+            balance.available -= amount;
+            balance.locked = locked;
+            Ok(())
+        })
     }
 
-    pub fn update_balance(&mut self, user_id: String, new_balance: Decimal) -> Result<(), String> {
-        self.wallet_map
-            .get_mut(&user_id)
-            .map(|wallet| wallet.balance = new_balance)
-            .ok_or_else(|| "Could not find wallet".to_string())
+    /// Hands `amount` of the locked balance back to the available one.
+    pub fn release(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.apply(user_id, currency, &[amount], |balance| {
+            if balance.locked < amount {
+                return Err(WalletError::InsufficientLocked {
+                    currency: currency.to_string(),
+                    locked: balance.locked,
+                    required: amount,
+                });
+            }
+
+            let available = checked(amount, balance.available.checked_add(amount))?;
+            balance.locked -= amount;
+            balance.available = available;
+            Ok(())
+        })
     }
 
-    pub fn get_balance(&self, user_id: &String) -> Option<Decimal> {
-        match self.wallet_map.get(user_id) {
-            Some(wallet) => Some(wallet.balance),
-            None => None,
+    /// Spends `reserved` of the locked balance on a charge of `amount`. Whatever the lock
+    /// doesn't cover comes out of the available balance and any surplus goes back to it.
+    pub fn commit(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        reserved: Decimal,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.apply(user_id, currency, &[reserved, amount], |balance| {
+            if balance.locked < reserved {
+                return Err(WalletError::InsufficientLocked {
+                    currency: currency.to_string(),
+                    locked: balance.locked,
+                    required: reserved,
+                });
+            }
+            let spendable = checked(reserved, balance.available.checked_add(reserved))?;
+            if spendable < amount {
+                return Err(WalletError::InsufficientFunds {
+                    currency: currency.to_string(),
+                    available: spendable,
+                    required: amount,
+                });
+            }
+
+            balance.locked -= reserved;
+            balance.available = spendable - amount;
+            Ok(())
+        })
+    }
+
+    /// Funds `currency` from outside the engine, returning the balance it leaves.
+    pub fn deposit(
+        &mut self,
+        user_id: &str,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<Balance, WalletError> {
+        if amount <= dec!(0) {
+            return Err(WalletError::InvalidAmount(amount));
         }
+
+        self.credit(user_id, currency, amount)?;
+        Ok(self.wallet_map[user_id].balance(currency))
+    }
+
+    pub fn snapshot(&self) -> Vec<Wallet> {
+        self.wallet_map.values().cloned().collect()
+    }
+
+    pub fn get(&self, user_id: &String) -> Option<Wallet> {
+        self.wallet_map.get(user_id).cloned()
     }
 
     pub fn create(&mut self, user_id: String) -> Result<(), String> {
@@ -39,14 +334,138 @@ impl Wallets {
             return Err("Wallet already exists".to_string());
         }
 
-        self.wallet_map.insert(
-            user_id.clone(),
-            Wallet {
-                user_id: user_id.clone(),
-                balance: dec!(10_000.0),
+        let mut wallet = Wallet::new(user_id.clone());
+        wallet.balances.insert(
+            COLLATERAL.to_string(),
+            Balance {
+                available: dec!(10_000.0),
+                locked: dec!(0),
             },
         );
 
+        self.storage.send(StorageMsg::UpsertWallet(wallet.clone()));
+        self.wallet_map.insert(user_id, wallet);
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a wallet holding `available` USDC with `locked` of it held for resting orders
+    fn wallets(available: Decimal, locked: Decimal) -> Wallets {
+        let mut wallets = Wallets::new(StorageHandle::disabled());
+        let mut wallet = Wallet::new("u1".to_string());
+        wallet
+            .balances
+            .insert(COLLATERAL.to_string(), Balance { available, locked });
+        wallets.hydrate(vec![wallet]);
+        wallets
+    }
+
+    fn balance(wallets: &Wallets) -> Balance {
+        wallets.wallet_map["u1"].balance(COLLATERAL)
+    }
+
+    #[test]
+    fn try_debit_without_funds_leaves_the_balance() {
+        let mut wallets = wallets(dec!(50), dec!(0));
+
+        let result = wallets.try_debit("u1", COLLATERAL, dec!(60));
+
+        assert!(matches!(result, Err(WalletError::InsufficientFunds { .. })));
+        assert_eq!(balance(&wallets).available, dec!(50));
+
+        wallets.try_debit("u1", COLLATERAL, dec!(50)).unwrap();
+        assert_eq!(balance(&wallets).available, dec!(0));
+    }
+
+    #[test]
+    fn reserve_moves_available_into_locked() {
+        let mut wallets = wallets(dec!(100), dec!(0));
+
+        wallets.reserve("u1", COLLATERAL, dec!(30)).unwrap();
+        assert_eq!(
+            balance(&wallets),
+            Balance {
+                available: dec!(70),
+                locked: dec!(30)
+            }
+        );
+
+        let result = wallets.reserve("u1", COLLATERAL, dec!(80));
+        assert!(matches!(result, Err(WalletError::InsufficientFunds { .. })));
+        assert_eq!(balance(&wallets).available, dec!(70));
+        assert_eq!(balance(&wallets).locked, dec!(30));
+    }
+
+    #[test]
+    fn release_more_than_locked_leaves_the_balance() {
+        let mut wallets = wallets(dec!(70), dec!(30));
+
+        let result = wallets.release("u1", COLLATERAL, dec!(40));
+        assert!(matches!(
+            result,
+            Err(WalletError::InsufficientLocked { .. })
+        ));
+        assert_eq!(balance(&wallets).available, dec!(70));
+        assert_eq!(balance(&wallets).locked, dec!(30));
+
+        wallets.release("u1", COLLATERAL, dec!(30)).unwrap();
+        assert_eq!(balance(&wallets).available, dec!(100));
+        assert_eq!(balance(&wallets).locked, dec!(0));
+    }
+
+    #[test]
+    fn commit_returns_the_surplus_of_the_lock() {
+        let mut wallets = wallets(dec!(70), dec!(30));
+
+        wallets
+            .commit("u1", COLLATERAL, dec!(30), dec!(25))
+            .unwrap();
+
+        assert_eq!(balance(&wallets).available, dec!(75));
+        assert_eq!(balance(&wallets).locked, dec!(0));
+    }
+
+    #[test]
+    fn commit_takes_a_shortfall_from_available() {
+        let mut wallets = wallets(dec!(70), dec!(30));
+
+        wallets
+            .commit("u1", COLLATERAL, dec!(30), dec!(40))
+            .unwrap();
+
+        assert_eq!(balance(&wallets).available, dec!(60));
+        assert_eq!(balance(&wallets).locked, dec!(0));
+    }
+
+    #[test]
+    fn commit_without_funds_leaves_the_balance() {
+        let mut wallets = wallets(dec!(10), dec!(30));
+
+        let result = wallets.commit("u1", COLLATERAL, dec!(30), dec!(50));
+        assert!(matches!(result, Err(WalletError::InsufficientFunds { .. })));
+
+        let result = wallets.commit("u1", COLLATERAL, dec!(40), dec!(20));
+        assert!(matches!(
+            result,
+            Err(WalletError::InsufficientLocked { .. })
+        ));
+
+        assert_eq!(balance(&wallets).available, dec!(10));
+        assert_eq!(balance(&wallets).locked, dec!(30));
+    }
+
+    #[test]
+    fn overflowing_credit_is_rejected() {
+        let mut wallets = wallets(Decimal::MAX, dec!(0));
+
+        let result = wallets.credit("u1", COLLATERAL, dec!(1));
+
+        assert_eq!(result, Err(WalletError::InvalidAmount(dec!(1))));
+        assert_eq!(balance(&wallets).available, Decimal::MAX);
+    }
+}
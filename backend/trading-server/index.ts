@@ -9,7 +9,9 @@ import { nanoid } from "nanoid";
 
 const app = new Hono();
 
-const requstMap = new Map<string, ({ message }: { message: string }) => void>();
+const requstMap = new Map<string, (response: any) => void>();
+
+const RESPONSE_TIMEOUT_MS = 5000;
 
 const kafka = new Kafka({
   clientId: 'my-app',
@@ -20,10 +22,54 @@ const producer = kafka.producer();
 
 await producer.connect();
 
+const consumer = kafka.consumer({ groupId: "trading-server" });
+
+await consumer.connect();
+await consumer.subscribe({ topics: ["orderResponses", "userResponses"] });
+
+// engine replies are keyed by the id of the request they answer
+await consumer.run({
+  eachMessage: async ({ message }) => {
+    const requestId = message.key?.toString();
+    if (!requestId || !message.value) return;
+
+    const resolve = requstMap.get(requestId);
+    if (!resolve) return;
+
+    requstMap.delete(requestId);
+    resolve(JSON.parse(message.value.toString()));
+  },
+});
+
+const SCHEMA_VERSION = 1;
+
+// the engine only accepts messages wrapped in its versioned envelope
+function envelope(type: string, payload: unknown, correlationId: string) {
+  return JSON.stringify({
+    type,
+    version: SCHEMA_VERSION,
+    correlation_id: correlationId,
+    timestamp: new Date().toISOString(),
+    payload,
+  });
+}
+
+function awaitResponse<T>(requestId: string): Promise<T> {
+  return new Promise((resolve, reject) => {
+    requstMap.set(requestId, resolve);
+    setTimeout(() => {
+      if (requstMap.delete(requestId)) {
+        reject(new Error(`timed out waiting for response to ${requestId}`));
+      }
+    }, RESPONSE_TIMEOUT_MS);
+  });
+}
+
 app.use("*", cors());
 
 app.post("/api/v1/signup", async (c) => {
   const { email }: { email: string } = await c.req.json();
+  const request_id = nanoid();
 
   // const token = jsonwebtoken.sign(email, "jwtsecret");
 
@@ -35,17 +81,24 @@ app.post("/api/v1/signup", async (c) => {
   //   );
   // }
 
+  const response = awaitResponse<CreateUserResponse>(request_id);
+
   await producer.send({
     topic: "priceUpdate",
     messages: [
       {
         key: "createUser",
-        value: email,
+        value: envelope("createUser", { request_id, email }, request_id),
       }
     ]
   });
 
-  return c.json({ ok: true });
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ success: false, error: "Signup timed out" }, 504);
+  }
 });
 
 app.post("/api/v1/signin", async (c) => {
@@ -68,18 +121,257 @@ app.post("/api/v1/order/open", async (c) => {
   const order_id = nanoid();
 
   const order = { ...orderRequest, order_id, user_id };
+  const response = awaitResponse<OpenOrderResponse>(order_id);
 
   await producer.send({
     topic: "priceUpdate",
     messages: [
       {
         key: "order",
-        value: JSON.stringify(order),
+        value: envelope("order", order, order_id),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ order_id, success: false, error: "Order timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/order/close", async (c) => {
+  // qty is optional, without it the whole position is closed
+  const { order_id, qty }: { order_id: string; qty?: number } = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<CloseOrderResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "closeOrder",
+        value: envelope("closeOrder", { request_id, order_id, user_id, qty }, request_id),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, order_id, success: false, message: "Close timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/order/cancel", async (c) => {
+  const { order_id }: { order_id: string } = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<CancelOrderResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "cancelOrder",
+        value: envelope("cancelOrder", { request_id, order_id, user_id }, request_id),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, order_id, success: false, error: "Cancel timed out" }, 504);
+  }
+});
+
+app.get("/api/v1/positions", async (c) => {
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<GetListResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "listPositions",
+        value: envelope("listPositions", { request_id, user_id }, request_id),
+      }
+    ]
+  });
+
+  try {
+    return c.json(await response);
+  } catch {
+    return c.json({ error: "Request timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/account/margin-mode", async (c) => {
+  const { mode }: { mode: MarginMode } = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<SetMarginModeResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "setMarginMode",
+        value: envelope("setMarginMode", { request_id, user_id, mode }, request_id),
       }
     ]
   });
 
-  return c.json({ message: "sup nigga" });
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, success: false, error: "Request timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/account/position-mode", async (c) => {
+  const { mode }: { mode: PositionMode } = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<SetPositionModeResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "setPositionMode",
+        value: envelope("setPositionMode", { request_id, user_id, mode }, request_id),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, success: false, error: "Request timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/wallet/deposit", async (c) => {
+  const { currency, amount }: DepositRequest = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<DepositResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "deposit",
+        value: envelope("deposit", { request_id, user_id, currency, amount }, request_id),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, success: false, error: "Request timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/positions/targets", async (c) => {
+  const { position_id, target_mode, stop_loss, take_profit }: SetTargetsRequest = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<SetTargetsResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "setTargets",
+        value: envelope(
+          "setTargets",
+          { request_id, user_id, position_id, target_mode, stop_loss, take_profit },
+          request_id,
+        ),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, success: false, error: "Request timed out" }, 504);
+  }
+});
+
+app.post("/api/v1/positions/modify", async (c) => {
+  const {
+    position_id,
+    target_mode,
+    stop_loss,
+    take_profit,
+    clear_stop_loss,
+    clear_take_profit,
+    margin_delta,
+  }: ModifyPositionRequest = await c.req.json();
+  const headers = c.req.header();
+  const user_id = headers.authorization?.split(' ')[1];
+  const request_id = nanoid();
+
+  const response = awaitResponse<ModifyPositionResponse>(request_id);
+
+  await producer.send({
+    topic: "priceUpdate",
+    messages: [
+      {
+        key: "modifyPosition",
+        value: envelope(
+          "modifyPosition",
+          {
+            request_id,
+            user_id,
+            position_id,
+            target_mode,
+            stop_loss,
+            take_profit,
+            clear_stop_loss,
+            clear_take_profit,
+            margin_delta,
+          },
+          request_id,
+        ),
+      }
+    ]
+  });
+
+  try {
+    const result = await response;
+    return c.json(result, result.success ? 200 : 400);
+  } catch {
+    return c.json({ request_id, success: false, error: "Request timed out" }, 504);
+  }
 });
 
 export default app;
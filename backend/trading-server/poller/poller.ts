@@ -99,9 +99,11 @@ async function startProducer() {
               key: "price",
               partition: 0,
               value: JSON.stringify({
-                BTC,
-                ETH,
-                SOL,
+                type: "price",
+                version: 1,
+                correlation_id: null,
+                timestamp: new Date().toISOString(),
+                payload: { BTC, ETH, SOL },
               }),
             },
           ],
@@ -1,9 +1,163 @@
 type OpenOrderRequest = {
   qty: number;
   asset: string;
-  margin: number;
+  margin?: number;
   stop_loss?: number;
   take_profit?: number;
+  target_mode?: TargetMode;
   leverage?: number;
+  order_type?: "market" | "limit" | "stop_market" | "stop_limit";
+  price?: number;
+  trigger_price?: number;
+  time_in_force?: "gtc" | "ioc" | "fok";
+  trailing_stop?: { kind: TrailKind; value: number };
 };
 
+type TargetMode = "price" | "pnl";
+
+type TrailKind = "distance" | "percent";
+
+type TrailingStop = {
+  kind: TrailKind;
+  value: string;
+  best_price: string;
+  trigger_price: string;
+};
+
+
+type Position = {
+  position_id: string;
+  asset: string;
+  entry_price: string;
+  qty: string;
+  pnl: string;
+  margin: string;
+  stop_loss: string | null;
+  take_profit: string | null;
+  target_mode: TargetMode;
+  leverage: string;
+  liquidation_price: string;
+  trailing_stop: TrailingStop | null;
+};
+
+type OpenOrderResponse = {
+  order_id: string;
+  success: boolean;
+  status: "filled" | "resting" | "expired" | null;
+  error: string | null;
+  entry_price: string | null;
+  position: Position | null;
+};
+
+type Settlement = {
+  entry_price: string;
+  exit_price: string;
+  qty: string;
+  fees: string;
+  realized_pnl: string;
+  returned_margin: string;
+  amount: string;
+};
+
+type ClosedPosition = {
+  position: Position;
+  settlement: Settlement;
+  remaining: Position | null;
+};
+
+type CloseOrderResponse = {
+  request_id: string;
+  order_id: string;
+  success: boolean;
+  message: string;
+  closed: ClosedPosition | null;
+};
+
+type GetListResponse = {
+  request_id: string;
+  positions: Position[];
+};
+
+type CreateUserResponse = {
+  request_id: string;
+  success: boolean;
+  user_id: string | null;
+  error: string | null;
+};
+
+type MarginMode = "isolated" | "cross";
+
+type SetMarginModeResponse = {
+  request_id: string;
+  success: boolean;
+  mode: MarginMode | null;
+  error: string | null;
+};
+
+type PositionMode = "hedge" | "one_way";
+
+type SetPositionModeResponse = {
+  request_id: string;
+  success: boolean;
+  mode: PositionMode | null;
+  error: string | null;
+};
+
+type Currency = "USDC" | "BTC" | "ETH" | "SOL";
+
+type Balance = {
+  available: string;
+  locked: string;
+};
+
+type DepositRequest = {
+  currency: Currency;
+  amount: number;
+};
+
+type DepositResponse = {
+  request_id: string;
+  success: boolean;
+  currency: Currency;
+  balance: Balance | null;
+  error: string | null;
+};
+
+type CancelOrderResponse = {
+  request_id: string;
+  order_id: string;
+  success: boolean;
+  error: string | null;
+  released: string | null;
+};
+
+type SetTargetsRequest = {
+  position_id: string;
+  target_mode?: TargetMode;
+  stop_loss?: number;
+  take_profit?: number;
+};
+
+type SetTargetsResponse = {
+  request_id: string;
+  success: boolean;
+  position: Position | null;
+  error: string | null;
+};
+
+type ModifyPositionRequest = {
+  position_id: string;
+  target_mode?: TargetMode;
+  stop_loss?: number;
+  take_profit?: number;
+  clear_stop_loss?: boolean;
+  clear_take_profit?: boolean;
+  margin_delta?: number;
+};
+
+type ModifyPositionResponse = {
+  request_id: string;
+  success: boolean;
+  position: Position | null;
+  error: string | null;
+};
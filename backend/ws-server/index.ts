@@ -18,7 +18,11 @@ client.subscribe("liquidations", (message: string) => {
     const ws = user_to_ws.get(position.user_id);
     const serialized = serialize({
       event: "force-liquidation",
-      positionId: position.position_id
+      positionId: position.position_id,
+      reason: position.reason,
+      exitPrice: position.exit_price,
+      realizedPnl: position.realized_pnl,
+      fees: position.fees
     });
     if (serialized) {
       ws?.send(serialized)
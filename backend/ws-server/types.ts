@@ -1,7 +1,17 @@
+export type CloseReason = "liquidation" | "stop_loss" | "trailing_stop" | "take_profit";
+
 export type LiquidationMessage = {
   positions: {
     user_id: string,
-    position_id: string
+    position_id: string,
+    asset: string,
+    reason: CloseReason,
+    qty: string,
+    entry_price: string,
+    exit_price: string,
+    realized_pnl: string,
+    fees: string,
+    timestamp: string
   }[]
 }
 